@@ -0,0 +1,47 @@
+use bevy::prelude::*;
+use bevy_pg_editor_tools::prelude::BrushType;
+use bevy_pg_terrain_editor_tools::prelude::{HeightBrushType, PlaneToEdit, TerrainHeightBrush, TerrainHeightfield, plane_distance};
+use criterion::{Criterion, black_box, criterion_group, criterion_main};
+
+const SIZE: usize = 1024;
+const RADIUS: f32 = 8.0;
+
+fn heightfield() -> (PlaneToEdit, TerrainHeightfield) {
+    let plane = PlaneToEdit::new((SIZE - 1) as f32, (SIZE - 1) as f32, SIZE as u32 - 2);
+    let heightfield = TerrainHeightfield::new(&plane);
+    return (plane, heightfield);
+}
+
+fn lookup(c: &mut Criterion) {
+    let (_, heightfield) = heightfield();
+    let plane_transform = GlobalTransform::default();
+    let loc = Vec3::new(100.0, 0.0, -50.0);
+    let mut group = c.benchmark_group("brush_lookup_1024x1024");
+    group.sample_size(10);
+    group.bench_function("full_scan", |b| b.iter(|| {
+        (0..heightfield.len())
+            .filter(|index| plane_distance(&plane_transform, black_box(loc), heightfield.local_position(*index)) <= RADIUS)
+            .count()
+    }));
+    group.bench_function("grid", |b| b.iter(|| {
+        heightfield.indices_within(&plane_transform, black_box(loc), RADIUS).len()
+    }));
+    group.finish();
+}
+
+fn brush(c: &mut Criterion) {
+    let (plane, heightfield) = heightfield();
+    let mut world = World::new();
+    world.spawn((heightfield, plane, Transform::default(), GlobalTransform::default()));
+    let mut height_brush = TerrainHeightBrush::new(HeightBrushType::Value(0.1));
+    let mut group = c.benchmark_group("brush_apply_1024x1024");
+    group.sample_size(10);
+    group.bench_function("height_value", |b| b.iter(|| {
+        height_brush.apply(&mut world, black_box(Vec3::new(100.0, 0.0, -50.0)), RADIUS);
+        height_brush.done(&mut world);
+    }));
+    group.finish();
+}
+
+criterion_group!(benches, lookup, brush);
+criterion_main!(benches);
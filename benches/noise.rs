@@ -0,0 +1,93 @@
+use bevy::prelude::*;
+use bevy::ecs::system::SystemState;
+use bevy_pg_editor_tools::prelude::BrushType;
+use bevy_pg_terrain_editor_tools::prelude::{ColorBrushType, HeightBrushType, Noise, NoiseType, PlaneToEdit, TerrainHeightfield, TerrainColorBrush, TerrainHeightBrush};
+use criterion::{Criterion, black_box, criterion_group, criterion_main};
+
+const SIZE: usize = 256;
+
+fn fbm_noise() -> Noise {
+    let mut noise = Noise::new();
+    noise.typ = NoiseType::FBMPerlin;
+    noise.scale = 0.05;
+    return noise;
+}
+
+fn grid() -> Vec<Vec3> {
+    let mut locs: Vec<Vec3> = Vec::with_capacity(SIZE*SIZE);
+    for z in 0..SIZE {
+        for x in 0..SIZE {
+            locs.push(Vec3::new(x as f32 - SIZE as f32*0.5, 0.0, z as f32 - SIZE as f32*0.5));
+        }
+    }
+    return locs;
+}
+
+fn plane_world() -> World {
+    let mut world = World::new();
+    let plane = PlaneToEdit::new((SIZE - 1) as f32, (SIZE - 1) as f32, SIZE as u32 - 2);
+    world.spawn((
+        TerrainHeightfield::new(&plane),
+        plane,
+        Transform::default(),
+        GlobalTransform::default()
+    ));
+    return world;
+}
+
+// What the height noise brush did before functions were cached, same vertices and the same writes
+fn uncached_height_noise(world: &mut World, noises: &[Noise], value: f32, loc: Vec3, radius: f32) {
+    let mut system_state: SystemState<Query<(&GlobalTransform, &mut TerrainHeightfield)>> = SystemState::new(world);
+    let mut planes = system_state.get_mut(world);
+    for (plane_transform, mut heightfield) in planes.iter_mut(){
+        let local_loc = plane_transform.affine().inverse().transform_point3(loc);
+        for (index, _) in heightfield.indices_within(plane_transform, local_loc, radius){
+            let local_pos = heightfield.local_position(index);
+            let height: f32 = noises.iter().map(|noise| noise.apply_uncached(local_pos)).sum::<f32>()*value;
+            heightfield.set_height(index, height);
+        }
+    }
+}
+
+fn noise_sampling(c: &mut Criterion) {
+    let noise = fbm_noise();
+    let locs = grid();
+    let mut group = c.benchmark_group("noise_sampling_256x256");
+    group.sample_size(10);
+    group.bench_function("uncached", |b| b.iter(|| {
+        locs.iter().map(|loc| noise.apply_uncached(black_box(*loc))).sum::<f32>()
+    }));
+    group.bench_function("cached", |b| b.iter(|| {
+        locs.iter().map(|loc| noise.apply(black_box(*loc))).sum::<f32>()
+    }));
+    group.finish();
+}
+
+fn noise_brushes(c: &mut Criterion) {
+    let mut group = c.benchmark_group("noise_brush_256x256");
+    group.sample_size(10);
+
+    let mut world = plane_world();
+    let mut height_brush = TerrainHeightBrush::new(HeightBrushType::Noise((vec![fbm_noise()], 1.0)));
+    group.bench_function("height_noise", |b| b.iter(|| {
+        height_brush.apply(&mut world, Vec3::ZERO, SIZE as f32);
+        height_brush.done(&mut world);
+    }));
+
+    let mut world = plane_world();
+    let noises = vec![fbm_noise()];
+    group.bench_function("height_noise_uncached", |b| b.iter(|| {
+        uncached_height_noise(&mut world, &noises, 1.0, Vec3::ZERO, SIZE as f32);
+    }));
+
+    let mut world = plane_world();
+    let mut color_brush = TerrainColorBrush::new(ColorBrushType::Noise{data: vec![fbm_noise()], value: 1.0, clr: [0.2, 0.6, 0.2, 1.0]});
+    group.bench_function("color_noise", |b| b.iter(|| {
+        color_brush.apply(&mut world, Vec3::ZERO, SIZE as f32);
+        color_brush.done(&mut world);
+    }));
+    group.finish();
+}
+
+criterion_group!(benches, noise_sampling, noise_brushes);
+criterion_main!(benches);
@@ -0,0 +1,82 @@
+use bevy::prelude::*;
+use bevy_pg_terrain_editor_tools::prelude::{ColorBrushType, HeightBrushType, Noise, NoiseType, PlaneToEdit, TerrainColorBrush, TerrainHeightBrush, TerrainHeightfield, ThermalErosion, thermal_erosion};
+use bevy_pg_terrain_editor_tools::terrain_brushes::smooth_heights;
+use criterion::{Criterion, criterion_group, criterion_main};
+
+const SIZE: usize = 512;
+
+fn fbm_noise() -> Noise {
+    let mut noise = Noise::new();
+    noise.typ = NoiseType::FBMPerlin;
+    noise.scale = 0.05;
+    return noise;
+}
+
+fn targets() -> (TerrainHeightfield, Vec<(usize, f32)>) {
+    let plane = PlaneToEdit::new((SIZE - 1) as f32, (SIZE - 1) as f32, SIZE as u32 - 2);
+    let heightfield = TerrainHeightfield::new(&plane);
+    let targets: Vec<(usize, f32)> = (0..heightfield.len()).map(|index| (index, 1.0)).collect();
+    return (heightfield, targets);
+}
+
+fn bumpy_heights() -> Vec<f32> {
+    return (0..SIZE*SIZE).map(|i| ((i % SIZE) as f32*0.3).sin()*4.0 + ((i / SIZE) as f32*0.17).cos()*2.0).collect();
+}
+
+fn height_noise(c: &mut Criterion) {
+    let (heightfield, targets) = targets();
+    let plane_transform = GlobalTransform::default();
+    let brush = TerrainHeightBrush::new(HeightBrushType::Noise((vec![fbm_noise()], 1.0)));
+
+    let mut group = c.benchmark_group("height_noise_512x512");
+    group.sample_size(10);
+    group.bench_function("serial", |b| b.iter(|| {
+        brush.vertex_heights(&heightfield, &plane_transform, Vec3::ZERO, &targets, false)
+    }));
+    group.bench_function("parallel", |b| b.iter(|| {
+        brush.vertex_heights(&heightfield, &plane_transform, Vec3::ZERO, &targets, true)
+    }));
+    group.finish();
+}
+
+fn color_noise(c: &mut Criterion) {
+    let (heightfield, targets) = targets();
+    let plane_transform = GlobalTransform::default();
+    let brush = TerrainColorBrush::new(ColorBrushType::Noise{data: vec![fbm_noise()], value: 1.0, clr: [0.2, 0.6, 0.2, 1.0]});
+
+    let mut group = c.benchmark_group("color_noise_512x512");
+    group.sample_size(10);
+    group.bench_function("serial", |b| b.iter(|| {
+        brush.vertex_colors(&heightfield, &plane_transform, Vec3::ZERO, &targets, false)
+    }));
+    group.bench_function("parallel", |b| b.iter(|| {
+        brush.vertex_colors(&heightfield, &plane_transform, Vec3::ZERO, &targets, true)
+    }));
+    group.finish();
+}
+
+fn grid_modes(c: &mut Criterion) {
+    let (_, targets) = targets();
+    let heights = bumpy_heights();
+    let thermal = ThermalErosion{iterations: 5, min_movement: 0.0, ..ThermalErosion::new()};
+
+    let mut group = c.benchmark_group("grid_modes_512x512");
+    group.sample_size(10);
+    for parallel in [false, true] {
+        let name = if parallel {"parallel"} else {"serial"};
+        group.bench_function(format!("smooth_{name}"), |b| b.iter(|| {
+            let mut heights = heights.clone();
+            smooth_heights(&mut heights, SIZE, SIZE, &targets, 4, parallel);
+            heights
+        }));
+        group.bench_function(format!("thermal_{name}"), |b| b.iter(|| {
+            let mut heights = heights.clone();
+            thermal_erosion(&mut heights, SIZE, SIZE, Vec2::ONE, &thermal, None, parallel);
+            heights
+        }));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, height_noise, color_noise, grid_modes);
+criterion_main!(benches);
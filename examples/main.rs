@@ -6,10 +6,11 @@ use bevy::picking::hover::HoverMap;
 use bevy::window::PrimaryWindow;
 use bevy_enhanced_input::prelude::*;
 use bevy_pg_editor_tools::prelude::{WorldPos, PGEditorToolsPlugin, PGEditorBrushSelectPlugin, BrushSelectController, BrushSettings, brush_select_controller};
-use bevy_pg_terrain_editor_tools::noises::Noise;
-use bevy_pg_terrain_editor_tools::prelude::{HeightBrushType, ColorBrushType, PlaneToEdit, SpawnVertices, 
-    TerrainColorBrush, TerrainEditorVertexPlugin, TerrainHeightBrush, 
-    TerrainVertexController, plane_mesh, terrain_vertex_controller
+use bevy_pg_terrain_editor_tools::prelude::{HeightBrushType, PlaneToEdit, SpawnVertices, 
+    TerrainEditorVertexPlugin, TerrainHeightBrush, 
+    TerrainVertexController, TerrainName, LoadTerrain, plane_mesh, terrain_vertex_controller,
+    TerrainBrushPresetPlugin, BrushPresetLibrary, BrushPresets, ActivateBrushPreset, TerrainRayHit,
+    ChunkLayout, ChunkedTerrain, TerrainChunk, LoadChunkedTerrain, spawn_chunked_terrain
 };
 
 fn main() {
@@ -20,23 +21,17 @@ fn main() {
         .add_input_context::<TerrainVertexController>()
         .insert_resource(AmbientLight{color: Color::from(WHITE), brightness: 900.0, ..default()})
         .add_plugins(TerrainEditorVertexPlugin::new(1.0))
+        .add_plugins(TerrainBrushPresetPlugin::new("brushes/default.brushes.json"))
         .add_plugins(PGEditorToolsPlugin)
         .add_plugins(PGEditorBrushSelectPlugin)
         .add_systems(Startup, init)
         .add_systems(Update, hover_plane)
         .add_systems(Update, switch.run_if(input_just_pressed(KeyCode::KeyS)))
+        .add_systems(Update, load.run_if(input_just_pressed(KeyCode::KeyL)))
         .run();
 
 }
 
-#[derive(Resource)]
-pub enum CurrentBrush {
-    HeightsValue,
-    HeightsNoise,
-    Color
-}
-
-
 fn init(
     mut commands:      Commands,
     mut meshes:        ResMut<Assets<Mesh>>,
@@ -45,56 +40,82 @@ fn init(
 ){
 
     brushsettings.radius = 1.0;
-    brushsettings.typ = Box::new(TerrainHeightBrush{typ: HeightBrushType::Value(1.0), reselection: true});
-    commands.insert_resource(CurrentBrush::HeightsValue);
+    brushsettings.typ = Box::new(TerrainHeightBrush::new(HeightBrushType::Value(1.0)));
     // brushsettings.typ = Box::new(TerrainColorBrush{color: [0.5, 0.5, 0.8, 1.0]});
-    // brushsettings.typ = Box::new(TerrainColorBrush{typ: ColorBrushType::Range { min: 0.0, max: 5.0, min_clr: [0.0, 0.0, 0.0, 1.0], max_clr:[1.0, 1.0, 1.0, 1.0] }});
-    // brushsettings.typ = Box::new(TerrainHeightBrush{typ: HeightBrushType::Noise((vec![Noise::new()], 1.0)), reselection: true});
+    // brushsettings.typ = Box::new(TerrainColorBrush::new(ColorBrushType::Range { min: 0.0, max: 5.0, min_clr: [0.0, 0.0, 0.0, 1.0], max_clr:[1.0, 1.0, 1.0, 1.0] }));
+    // brushsettings.typ = Box::new(TerrainHeightBrush::new(HeightBrushType::Noise((vec![Noise::new()], 1.0))));
 
     commands.spawn((
         brush_select_controller(),
         terrain_vertex_controller()
     ));
 
-    let plane_entity = commands.spawn(
-        (
-            plane_mesh(40.0, 40.0, 12, &mut meshes),
-            MeshMaterial3d(materials.add(StandardMaterial::from_color(Color::WHITE))),
-            Transform::from_translation(Vec3::new(0.0, 0.0, 0.0))
-        )
-    ).id();
+    let material = MeshMaterial3d(materials.add(StandardMaterial::from_color(Color::WHITE)));
+    for (name, x) in [("terrain", 0.0), ("terrain_east", 40.0)]{
+        let plane_entity = commands.spawn(
+            (
+                plane_mesh(40.0, 40.0, 12, &mut meshes),
+                TerrainName(String::from(name)),
+                material.clone(),
+                Transform::from_translation(Vec3::new(x, 0.0, 0.0))
+            )
+        ).id();
+        commands.trigger(SpawnVertices{plane_entity});
+    }
+
+    // 2x2 chunks edited as one 40x40 surface
+    let terrain_entity = spawn_chunked_terrain(&mut commands, &mut meshes, ChunkLayout::new(2, 2, 20.0, 20.0, 6), material.clone());
+    commands.entity(terrain_entity).insert((
+        TerrainName(String::from("terrain_chunks")),
+        Transform::from_translation(Vec3::new(20.0, 0.0, -40.0))
+    ));
 
     commands.spawn(
         (
             Camera3d::default(),
-            Transform::from_xyz(25.0, 50.0, 25.0).looking_at(Vec3::new(0.0, 0.0, 0.0), Vec3::Y)
+            Transform::from_xyz(45.0, 80.0, 45.0).looking_at(Vec3::new(20.0, 0.0, -20.0), Vec3::Y)
         )
     );
-
-    commands.trigger(SpawnVertices{plane_entity});
 }
 
 fn switch(
-    mut brushsettings: ResMut<BrushSettings>,
-    mut current_brush: ResMut<CurrentBrush>
+    mut commands: Commands,
+    library:      Res<BrushPresetLibrary>,
+    presets:      Res<Assets<BrushPresets>>
 ){
-    match *current_brush {
-        CurrentBrush::HeightsValue => {
-            *current_brush = CurrentBrush::HeightsNoise;
-            brushsettings.typ = Box::new(TerrainHeightBrush{typ: HeightBrushType::Noise((vec![Noise::new()], 1.0)), reselection: true});
-            info!("changed to height noise");
-        }
-        CurrentBrush::HeightsNoise => {
-            *current_brush = CurrentBrush::Color;
-            brushsettings.typ = Box::new(TerrainColorBrush{typ: ColorBrushType::Range { min: -5.0, max: 5.0, min_clr: [0.0, 0.0, 0.0, 1.0], max_clr:[1.0, 1.0, 1.0, 1.0] }});
-            info!("changed to color range");
-        }
-        CurrentBrush::Color => {
-            *current_brush = CurrentBrush::HeightsValue;
-            brushsettings.typ = Box::new(TerrainHeightBrush{typ: HeightBrushType::Value(1.0), reselection: true});
-            info!("changed to height value");
-        }
+    let Some(presets) = presets.get(&library.handle) else {return;};
+    let names = presets.names();
+    if names.is_empty(){
+        return;
     }
+    let next: usize = match &library.active {
+        Some(active) => {names.iter().position(|name| name == active).map(|i| (i+1) % names.len()).unwrap_or(0)}
+        None => {0}
+    };
+    info!("changed to {}", names[next]);
+    commands.trigger(ActivateBrushPreset{name: names[next].clone()});
+}
+
+fn load(
+    mut commands: Commands,
+    planes:       Query<Entity, (With<PlaneToEdit>, Without<TerrainChunk>)>,
+    terrains:     Query<Entity, With<ChunkedTerrain>>
+){
+    for plane_entity in planes.iter(){
+        commands.entity(plane_entity).despawn();
+    }
+    for terrain_entity in terrains.iter(){
+        commands.entity(terrain_entity).despawn();
+    }
+    commands.trigger(LoadTerrain::new("assets/meshes/terrain.json"));
+    commands.trigger(LoadTerrain{
+        path: "assets/meshes/terrain_east.json".into(),
+        transform: Transform::from_translation(Vec3::new(40.0, 0.0, 0.0))
+    });
+    commands.trigger(LoadChunkedTerrain{
+        path: "assets/meshes/terrain_chunks.chunks.json".into(),
+        transform: Transform::from_translation(Vec3::new(20.0, 0.0, -40.0))
+    });
 }
 
 fn hover_plane(
@@ -102,7 +123,8 @@ fn hover_plane(
     primary:            Single<&Window, With<PrimaryWindow>>,
     camera:             Single<(&Camera, &GlobalTransform), With<Camera3d>>,
     nodes:              Query<Entity, With<Node>>,
-    planes:             Query<(&PlaneToEdit, &Transform)>,
+    planes:             Query<(&PlaneToEdit, &Mesh3d, &GlobalTransform)>,
+    meshes:             Res<Assets<Mesh>>,
     mut worldpos:       ResMut<WorldPos>
 ) {
     worldpos.reset();
@@ -129,14 +151,17 @@ fn hover_plane(
         let ray_origin: Vec3A = ray.origin.into();
         let ray_dir: Vec3A = Vec3A::from(*ray.direction);
 
-        for (plane, plane_transform) in planes.iter(){
-            if let Some(distance) = plane.ray_intersection(plane_transform.translation, plane_transform.scale, ray_origin, ray_dir){
-                if distance > 0.0 {
-                    let position: Vec3 = (ray_origin + ray_dir * distance).into();
-                    worldpos.set(position);
-                    return;
+        let mut closest: Option<TerrainRayHit> = None;
+        for (plane, plane_mesh3d, plane_transform) in planes.iter(){
+            let Some(mesh) = meshes.get(&plane_mesh3d.0) else {continue;};
+            if let Some(hit) = plane.raycast_mesh(mesh, plane_transform, ray_origin, ray_dir){
+                if closest.is_none_or(|closest_hit| hit.distance < closest_hit.distance) {
+                    closest = Some(hit);
                 }
             }
         }
+        if let Some(hit) = closest {
+            worldpos.set(hit.point);
+        }
     }
 }
@@ -0,0 +1,493 @@
+use bevy::prelude::*;
+use bevy::mesh::VertexAttributeValues;
+use serde::{Serialize, Deserialize};
+use std::collections::{HashMap, HashSet};
+
+use crate::planes::plane_mesh;
+use crate::heightfield::TerrainHeightfield;
+use crate::normals::{TerrainShading, grid_normal};
+
+// Grid of chunks_x by chunks_z plane_mesh chunks, each chunk_width by chunk_height with the same subdivisions.
+// Neighbouring chunks share their edge vertices, so the terrain has one global vertex grid.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ChunkLayout {
+    pub chunks_x: usize,
+    pub chunks_z: usize,
+    pub chunk_width: f32,
+    pub chunk_height: f32,
+    pub subdivisions: u32
+}
+
+impl ChunkLayout {
+    pub fn new(chunks_x: usize, chunks_z: usize, chunk_width: f32, chunk_height: f32, subdivisions: u32) -> Self {
+        ChunkLayout {
+            chunks_x,
+            chunks_z,
+            chunk_width,
+            chunk_height,
+            subdivisions
+        }
+    }
+
+    pub fn chunk_count(&self) -> usize {
+        return self.chunks_x*self.chunks_z;
+    }
+
+    pub fn chunk_index(&self, x: usize, z: usize) -> usize {
+        return z*self.chunks_x + x;
+    }
+
+    // Vertex columns and rows of a single chunk, as in PlaneToEdit::grid_size
+    pub fn chunk_grid_size(&self) -> (usize, usize) {
+        let count = self.subdivisions as usize + 2;
+        return (count, count);
+    }
+
+    // Vertex columns and rows of the whole terrain, shared edges counted once
+    pub fn grid_size(&self) -> (usize, usize) {
+        let (cols, rows) = self.chunk_grid_size();
+        return (self.chunks_x*(cols - 1) + 1, self.chunks_z*(rows - 1) + 1);
+    }
+
+    pub fn cell_size(&self) -> Vec2 {
+        let (cols, rows) = self.chunk_grid_size();
+        return Vec2::new(
+            self.chunk_width/(cols - 1) as f32,
+            self.chunk_height/(rows - 1) as f32
+        );
+    }
+
+    // Chunk center relative to the terrain center
+    pub fn chunk_translation(&self, x: usize, z: usize) -> Vec3 {
+        return Vec3::new(
+            (x as f32 + 0.5)*self.chunk_width - self.chunks_x as f32*self.chunk_width*0.5,
+            0.0,
+            (z as f32 + 0.5)*self.chunk_height - self.chunks_z as f32*self.chunk_height*0.5
+        );
+    }
+
+    // Global grid coordinates of a chunk vertex
+    pub fn global_coords(&self, chunk_x: usize, chunk_z: usize, index: usize) -> (usize, usize) {
+        let (cols, rows) = self.chunk_grid_size();
+        return (chunk_x*(cols - 1) + index % cols, chunk_z*(rows - 1) + index / cols);
+    }
+
+    // Every (chunk index, vertex index) holding the global vertex, up to four on chunk corners
+    pub fn copies(&self, gx: usize, gz: usize) -> Vec<(usize, usize)> {
+        let (cols, rows) = self.chunk_grid_size();
+        let mut copies: Vec<(usize, usize)> = Vec::with_capacity(4);
+        for (chunk_z, z) in axis_copies(gz, rows - 1, self.chunks_z){
+            for (chunk_x, x) in axis_copies(gx, cols - 1, self.chunks_x){
+                copies.push((self.chunk_index(chunk_x, chunk_z), z*cols + x));
+            }
+        }
+        return copies;
+    }
+
+    // The one copy heights are read from when the global grid is sampled
+    pub fn owner(&self, gx: usize, gz: usize) -> (usize, usize) {
+        let (cols, rows) = self.chunk_grid_size();
+        let chunk_x = (gx/(cols - 1)).min(self.chunks_x - 1);
+        let chunk_z = (gz/(rows - 1)).min(self.chunks_z - 1);
+        let x = gx - chunk_x*(cols - 1);
+        let z = gz - chunk_z*(rows - 1);
+        return (self.chunk_index(chunk_x, chunk_z), z*cols + x);
+    }
+
+    // Vertices held by more than one chunk
+    pub fn is_seam(&self, gx: usize, gz: usize) -> bool {
+        let (cols, rows) = self.chunk_grid_size();
+        let (global_cols, global_rows) = self.grid_size();
+        let seam_x = gx.is_multiple_of(cols - 1) && gx > 0 && gx < global_cols - 1;
+        let seam_z = gz.is_multiple_of(rows - 1) && gz > 0 && gz < global_rows - 1;
+        return seam_x || seam_z;
+    }
+}
+
+// (chunk, local coordinate) pairs along one axis for a global coordinate
+fn axis_copies(global: usize, cells: usize, count: usize) -> Vec<(usize, usize)> {
+    let mut copies: Vec<(usize, usize)> = Vec::with_capacity(2);
+    let chunk = global/cells;
+    if chunk > 0 && global.is_multiple_of(cells) {
+        copies.push((chunk - 1, cells));
+    }
+    if chunk < count {
+        copies.push((chunk, global - chunk*cells));
+    }
+    return copies;
+}
+
+// Root of a chunked terrain, chunks are its children and sit at layout.chunk_index(x, z)
+#[derive(Component, Clone, Debug)]
+pub struct ChunkedTerrain {
+    pub layout: ChunkLayout,
+    pub chunks: Vec<Entity>
+}
+
+impl ChunkedTerrain {
+    pub fn chunk(&self, x: usize, z: usize) -> Option<Entity> {
+        if x >= self.layout.chunks_x || z >= self.layout.chunks_z {
+            return None;
+        }
+        return self.chunks.get(self.layout.chunk_index(x, z)).copied();
+    }
+
+    // Chunk entities and vertex indices holding the global vertex
+    pub fn copies(&self, gx: usize, gz: usize) -> Vec<(Entity, usize)> {
+        return self.layout
+            .copies(gx, gz)
+            .into_iter()
+            .filter_map(|(chunk_index, index)| self.chunks.get(chunk_index).map(|chunk_entity| (*chunk_entity, index)))
+            .collect();
+    }
+}
+
+#[derive(Component, Clone, Copy, Debug)]
+pub struct TerrainChunk {
+    pub terrain_entity: Entity,
+    pub x: usize,
+    pub z: usize,
+    pub offset: Vec3 // chunk translation in the terrain, brushes sample local noises at offset + plane position
+}
+
+// Spawns the terrain root with a flat plane_mesh chunk per grid cell and returns the root entity
+pub fn spawn_chunked_terrain(
+    commands: &mut Commands,
+    meshes:   &mut ResMut<Assets<Mesh>>,
+    layout:   ChunkLayout,
+    material: MeshMaterial3d<StandardMaterial>
+) -> Entity {
+    let mut chunk_bundles = Vec::with_capacity(layout.chunk_count());
+    for _ in 0..layout.chunk_count() {
+        chunk_bundles.push(plane_mesh(layout.chunk_width, layout.chunk_height, layout.subdivisions, meshes));
+    }
+    return spawn_chunks(commands, layout, chunk_bundles, material);
+}
+
+// Chunk bundles are ordered by layout.chunk_index and must hold the chunk's Mesh3d and PlaneToEdit
+pub(crate) fn spawn_chunks<B: Bundle>(
+    commands:      &mut Commands,
+    layout:        ChunkLayout,
+    chunk_bundles: Vec<B>,
+    material:      MeshMaterial3d<StandardMaterial>
+) -> Entity {
+    let terrain_entity = commands.spawn((Transform::default(), Visibility::default())).id();
+    let mut chunks: Vec<Entity> = Vec::with_capacity(chunk_bundles.len());
+    for (chunk_index, chunk_bundle) in chunk_bundles.into_iter().enumerate(){
+        let x = chunk_index % layout.chunks_x;
+        let z = chunk_index / layout.chunks_x;
+        let offset = layout.chunk_translation(x, z);
+        let chunk_entity = commands.spawn((
+            chunk_bundle,
+            TerrainChunk{terrain_entity, x, z, offset},
+            material.clone(),
+            Transform::from_translation(offset)
+        )).id();
+        chunks.push(chunk_entity);
+    }
+    commands.entity(terrain_entity).add_children(&chunks);
+    commands.entity(terrain_entity).insert(ChunkedTerrain{layout, chunks});
+    return terrain_entity;
+}
+
+// Brushes edit every chunk on their own, so a shared vertex can get a different value in each copy.
+// The copies that were changed are averaged and written to all of them before the meshes are updated.
+pub(crate) fn sync_chunk_seams(
+    terrains:   Query<&ChunkedTerrain>,
+    mut chunks: Query<(&TerrainChunk, &mut TerrainHeightfield)>
+){
+    let mut seams: HashMap<Entity, HashSet<(usize, usize)>> = HashMap::new();
+    for (chunk, heightfield) in chunks.iter_mut(){
+        if !heightfield.is_changed() || heightfield.dirty_indices().is_empty() {
+            continue;
+        }
+        let Ok(terrain) = terrains.get(chunk.terrain_entity) else {continue;};
+        for index in heightfield.dirty_indices().iter(){
+            let (gx, gz) = terrain.layout.global_coords(chunk.x, chunk.z, *index);
+            if terrain.layout.is_seam(gx, gz) {
+                seams.entry(chunk.terrain_entity).or_default().insert((gx, gz));
+            }
+        }
+    }
+
+    for (terrain_entity, coords) in seams.iter(){
+        let Ok(terrain) = terrains.get(*terrain_entity) else {continue;};
+        for (gx, gz) in coords.iter(){
+            let copies = terrain.copies(*gx, *gz);
+            let mut height: f32 = 0.0;
+            let mut clr: [f32;4] = [0.0; 4];
+            let mut count: usize = 0;
+            for (chunk_entity, index) in copies.iter(){
+                let Ok((_, heightfield)) = chunks.get(*chunk_entity) else {continue;};
+                if !heightfield.is_vertex_dirty(*index) {
+                    continue;
+                }
+                height += heightfield.heights[*index];
+                for (channel, value) in clr.iter_mut().zip(heightfield.colors[*index]){
+                    *channel += value;
+                }
+                count += 1;
+            }
+            if count == 0 {
+                continue;
+            }
+            height /= count as f32;
+            clr = clr.map(|channel| channel/count as f32);
+
+            for (chunk_entity, index) in copies.iter(){
+                let Ok((_, heightfield)) = chunks.get(*chunk_entity) else {continue;};
+                if *index >= heightfield.len() || (heightfield.heights[*index] == height && heightfield.colors[*index] == clr) {
+                    continue;
+                }
+                let Ok((_, mut heightfield)) = chunks.get_mut(*chunk_entity) else {continue;};
+                heightfield.set_height(*index, height);
+                heightfield.set_color(*index, clr);
+            }
+        }
+    }
+}
+
+type SeamChunks<'w, 's> = Query<'w, 's, (&'static TerrainChunk, Ref<'static, TerrainHeightfield>, &'static Mesh3d, Option<Ref<'static, TerrainShading>>)>;
+
+// A chunk mesh only sees its own vertices, so its edge normals are one sided.
+// Seam vertices of changed chunks get their normals from the global grid instead, in every copy.
+// Flat shaded chunks are left alone, each of their faces lies inside one chunk.
+pub(crate) fn chunk_seam_normals(
+    terrains:   Query<&ChunkedTerrain>,
+    chunks:     SeamChunks,
+    mut meshes: ResMut<Assets<Mesh>>
+){
+    let mut seams: HashMap<Entity, HashSet<(usize, usize)>> = HashMap::new();
+    for (chunk, heightfield, _, shading) in chunks.iter(){
+        if !heightfield.is_changed() && !shading.as_ref().is_some_and(|shading| shading.is_changed()) {
+            continue;
+        }
+        let Ok(terrain) = terrains.get(chunk.terrain_entity) else {continue;};
+        let (cols, rows) = terrain.layout.chunk_grid_size();
+        let border = (0..cols).flat_map(|x| [(x, 0), (x, rows - 1)])
+            .chain((0..rows).flat_map(|z| [(0, z), (cols - 1, z)]));
+        for (x, z) in border {
+            let (gx, gz) = terrain.layout.global_coords(chunk.x, chunk.z, z*cols + x);
+            if terrain.layout.is_seam(gx, gz) {
+                seams.entry(chunk.terrain_entity).or_default().insert((gx, gz));
+            }
+        }
+    }
+
+    for (terrain_entity, coords) in seams.iter(){
+        let Ok(terrain) = terrains.get(*terrain_entity) else {continue;};
+        if terrain.chunks.len() != terrain.layout.chunk_count() {
+            continue;
+        }
+        let (global_cols, global_rows) = terrain.layout.grid_size();
+        let cell = terrain.layout.cell_size();
+        // Chunks only differ by translation, so terrain space normals are chunk space normals
+        let position = |gx: usize, gz: usize| {
+            let (chunk_index, index) = terrain.layout.owner(gx, gz);
+            let height = chunks
+                .get(terrain.chunks[chunk_index])
+                .ok()
+                .and_then(|(_, heightfield, _, _)| heightfield.heights.get(index).copied())
+                .unwrap_or(0.0);
+            Vec3::new(gx as f32*cell.x, height, gz as f32*cell.y)
+        };
+
+        let mut normals: HashMap<Entity, Vec<(usize, [f32;3])>> = HashMap::new();
+        for (gx, gz) in coords.iter(){
+            let normal = grid_normal(&position, global_cols, global_rows, *gx, *gz);
+            for (chunk_entity, index) in terrain.copies(*gx, *gz){
+                normals.entry(chunk_entity).or_default().push((index, normal.into()));
+            }
+        }
+
+        for (chunk_entity, chunk_normals) in normals.iter(){
+            let Ok((_, _, mesh3d, shading)) = chunks.get(*chunk_entity) else {continue;};
+            if shading.is_some_and(|shading| *shading == TerrainShading::Flat) {
+                continue;
+            }
+            let Some(mesh) = meshes.get_mut(&mesh3d.0) else {continue;};
+            let Some(VertexAttributeValues::Float32x3(mesh_normals)) = mesh.attribute_mut(Mesh::ATTRIBUTE_NORMAL) else {continue;};
+            for (index, normal) in chunk_normals.iter(){
+                if let Some(mesh_normal) = mesh_normals.get_mut(*index) {
+                    *mesh_normal = *normal;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy::ecs::system::RunSystemOnce;
+    use crate::heightfield::init_heightfields;
+    use crate::vertex::vertex_changed;
+    use crate::terrain_brushes::{TerrainHeightBrush, HeightBrushType};
+    use crate::save::{save_chunks_to_file, load_chunks_from_file};
+    use bevy_pg_editor_tools::prelude::BrushType;
+
+    fn chunked_app(layout: ChunkLayout) -> (App, Entity) {
+        let mut app = App::new();
+        app
+        .add_plugins(bevy::transform::TransformPlugin)
+        .init_resource::<Assets<Mesh>>()
+        .add_systems(PreUpdate, init_heightfields)
+        .add_systems(Update, (sync_chunk_seams, vertex_changed, chunk_seam_normals).chain());
+        let terrain_entity = app.world_mut().run_system_once(move |mut commands: Commands, mut meshes: ResMut<Assets<Mesh>>| {
+            return spawn_chunked_terrain(&mut commands, &mut meshes, layout, MeshMaterial3d::default());
+        }).unwrap();
+        app.update();
+        return (app, terrain_entity);
+    }
+
+    fn mesh_attribute(app: &App, chunk_entity: Entity, attribute: bevy::mesh::MeshVertexAttribute) -> Vec<[f32;3]> {
+        let mesh3d = app.world().get::<Mesh3d>(chunk_entity).unwrap();
+        let mesh = app.world().resource::<Assets<Mesh>>().get(&mesh3d.0).unwrap();
+        return mesh.attribute(attribute).unwrap().as_float3().unwrap().to_vec();
+    }
+
+    #[test]
+    fn layout_grid_and_copies() {
+        let layout = ChunkLayout::new(2, 2, 4.0, 4.0, 2);
+        assert_eq!(layout.chunk_grid_size(), (4, 4));
+        assert_eq!(layout.grid_size(), (7, 7));
+
+        // Centre is a corner of all four chunks, seam edges are held by two and the rest by one
+        let (cols, rows) = layout.grid_size();
+        for gz in 0..rows {
+            for gx in 0..cols {
+                let copies = layout.copies(gx, gz);
+                let expected = match (gx == 3, gz == 3) {
+                    (true, true) => {4}
+                    (true, false) | (false, true) => {2}
+                    (false, false) => {1}
+                };
+                assert_eq!(copies.len(), expected, "({gx}, {gz})");
+                assert_eq!(layout.is_seam(gx, gz), expected > 1, "({gx}, {gz})");
+                assert!(copies.contains(&layout.owner(gx, gz)));
+                for (chunk_index, index) in copies {
+                    let (chunk_x, chunk_z) = (chunk_index % layout.chunks_x, chunk_index / layout.chunks_x);
+                    assert_eq!(layout.global_coords(chunk_x, chunk_z, index), (gx, gz));
+                }
+            }
+        }
+        assert_eq!(layout.copies(3, 3), vec![(0, 15), (1, 12), (2, 3), (3, 0)]);
+    }
+
+    #[test]
+    fn seams_stay_shared_after_a_stroke() {
+        let layout = ChunkLayout::new(2, 2, 4.0, 4.0, 3);
+        let (mut app, terrain_entity) = chunked_app(layout);
+        let terrain = app.world().get::<ChunkedTerrain>(terrain_entity).unwrap().clone();
+
+        // Off centre stroke, it reaches over both seams with a different falloff on every vertex
+        let mut brush = TerrainHeightBrush::new(HeightBrushType::Value(1.5));
+        brush.started(app.world_mut());
+        brush.apply(app.world_mut(), Vec3::new(0.7, 0.0, -0.4), 2.5);
+        brush.done(app.world_mut());
+        app.update();
+
+        // A copy edited on its own is written to the other copies
+        let (chunk_entity, index) = terrain.copies(4, 2)[1];
+        app.world_mut().get_mut::<TerrainHeightfield>(chunk_entity).unwrap().set_height(index, -3.0);
+        app.update();
+
+        let (cols, rows) = layout.grid_size();
+        let chunk_positions: HashMap<Entity, Vec<[f32;3]>> = terrain.chunks
+            .iter()
+            .map(|chunk_entity| (*chunk_entity, mesh_attribute(&app, *chunk_entity, Mesh::ATTRIBUTE_POSITION)))
+            .collect();
+        let mut raised = 0;
+        for gz in 0..rows {
+            for gx in 0..cols {
+                let copies = terrain.copies(gx, gz);
+                let heights: Vec<f32> = copies.iter().map(|(chunk_entity, index)| app.world().get::<TerrainHeightfield>(*chunk_entity).unwrap().heights[*index]).collect();
+                let mesh_heights: Vec<f32> = copies.iter().map(|(chunk_entity, index)| chunk_positions[chunk_entity][*index][1]).collect();
+                assert!(heights.iter().all(|height| *height == heights[0]), "({gx}, {gz}): {heights:?}");
+                assert_eq!(heights, mesh_heights, "({gx}, {gz})");
+                if layout.is_seam(gx, gz) && heights[0] > 0.0 {
+                    raised += 1;
+                }
+            }
+        }
+        assert!(raised > 2);
+        assert_eq!(app.world().get::<TerrainHeightfield>(terrain.copies(4, 2)[0].0).unwrap().heights[terrain.copies(4, 2)[0].1], -3.0);
+    }
+
+    #[test]
+    fn seam_normals_match_on_both_sides() {
+        let layout = ChunkLayout::new(2, 2, 4.0, 4.0, 3);
+        let (mut app, terrain_entity) = chunked_app(layout);
+        let terrain = app.world().get::<ChunkedTerrain>(terrain_entity).unwrap().clone();
+
+        let (cols, rows) = layout.grid_size();
+        let height = |gx: usize, gz: usize| (gx as f32*1.1).sin()*1.5 + (gz*gz) as f32*0.1;
+        for gz in 0..rows {
+            for gx in 0..cols {
+                for (chunk_entity, index) in terrain.copies(gx, gz) {
+                    app.world_mut().get_mut::<TerrainHeightfield>(chunk_entity).unwrap().set_height(index, height(gx, gz));
+                }
+            }
+        }
+        app.update();
+
+        // Seam normals come from the global grid, as if the terrain was one mesh
+        let cell = layout.cell_size();
+        let position = |gx: usize, gz: usize| Vec3::new(gx as f32*cell.x, height(gx, gz), gz as f32*cell.y);
+        let chunk_normals: HashMap<Entity, Vec<[f32;3]>> = terrain.chunks
+            .iter()
+            .map(|chunk_entity| (*chunk_entity, mesh_attribute(&app, *chunk_entity, Mesh::ATTRIBUTE_NORMAL)))
+            .collect();
+        for gz in 0..rows {
+            for gx in 0..cols {
+                if !layout.is_seam(gx, gz) {
+                    continue;
+                }
+                let expected = grid_normal(&position, cols, rows, gx, gz);
+                for (chunk_entity, index) in terrain.copies(gx, gz) {
+                    let normal = Vec3::from(chunk_normals[&chunk_entity][index]);
+                    assert!(normal.distance(expected) < 1e-5, "({gx}, {gz}): {normal:?} != {expected:?}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn manifest_round_trip() {
+        let layout = ChunkLayout::new(3, 2, 6.0, 5.0, 4);
+        let (mut app, terrain_entity) = chunked_app(layout);
+        let terrain = app.world().get::<ChunkedTerrain>(terrain_entity).unwrap().clone();
+        for (chunk_index, chunk_entity) in terrain.chunks.iter().enumerate(){
+            let mut heightfield = app.world_mut().get_mut::<TerrainHeightfield>(*chunk_entity).unwrap();
+            for index in 0..heightfield.len() {
+                heightfield.set_height(index, (chunk_index*100 + index) as f32*0.01);
+            }
+        }
+        app.update();
+
+        let meshes: Vec<Mesh> = terrain.chunks
+            .iter()
+            .map(|chunk_entity| {
+                let mesh3d = app.world().get::<Mesh3d>(*chunk_entity).unwrap();
+                return app.world().resource::<Assets<Mesh>>().get(&mesh3d.0).unwrap().clone();
+            })
+            .collect();
+        let directory = std::env::temp_dir().join(format!("terrain_chunks_round_trip_{}", std::process::id()));
+        let manifest = directory.join("hills.chunks.json");
+        save_chunks_to_file(&layout, &meshes.iter().collect::<Vec<&Mesh>>(), &manifest).unwrap();
+        let (loaded_layout, loaded) = load_chunks_from_file(&manifest).unwrap();
+
+        assert_eq!(loaded_layout, layout);
+        assert_eq!(loaded.len(), layout.chunk_count());
+        for ((mesh, plane), saved) in loaded.iter().zip(meshes.iter()){
+            assert_eq!(plane.width, layout.chunk_width);
+            assert_eq!(plane.height, layout.chunk_height);
+            assert_eq!(plane.subdivisions, layout.subdivisions);
+            assert_eq!(
+                mesh.attribute(Mesh::ATTRIBUTE_POSITION).unwrap().as_float3().unwrap(),
+                saved.attribute(Mesh::ATTRIBUTE_POSITION).unwrap().as_float3().unwrap()
+            );
+        }
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,548 @@
+use bevy::prelude::*;
+use bevy::tasks::{ComputeTaskPool, ParallelSlice, TaskPool};
+use serde::{Serialize, Deserialize};
+
+use crate::planes::PlaneToEdit;
+use crate::history::{BrushHistory, VertexSnapshot};
+use crate::heightfield::TerrainHeightfield;
+use crate::terrain_brushes::{PARALLEL_THRESHOLD, PARALLEL_CHUNK_SIZE};
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HydraulicErosion {
+    pub iterations: usize, // number of droplets
+    pub seed: u32,
+    pub inertia: f32,
+    pub capacity: f32,
+    pub min_capacity: f32,
+    pub deposition: f32,
+    pub erosion: f32,
+    pub evaporation: f32,
+    pub gravity: f32,
+    pub max_lifetime: usize,
+    pub initial_water: f32,
+    pub initial_speed: f32
+}
+
+impl Default for HydraulicErosion {
+    fn default() -> Self {
+        HydraulicErosion::new()
+    }
+}
+
+impl HydraulicErosion {
+    pub fn new() -> Self {
+        HydraulicErosion {
+            iterations:    5000,
+            seed:          1,
+            inertia:       0.05,
+            capacity:      4.0,
+            min_capacity:  0.01,
+            deposition:    0.3,
+            erosion:       0.3,
+            evaporation:   0.01,
+            gravity:       4.0,
+            max_lifetime:  30,
+            initial_water: 1.0,
+            initial_speed: 1.0
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ThermalErosion {
+    pub talus_angle: f32, // degrees
+    pub rate: f32,
+    pub iterations: usize,
+    pub min_movement: f32 // stops once less material than this moves in an iteration
+}
+
+impl Default for ThermalErosion {
+    fn default() -> Self {
+        ThermalErosion::new()
+    }
+}
+
+impl ThermalErosion {
+    pub fn new() -> Self {
+        ThermalErosion {
+            talus_angle:  35.0,
+            rate:         0.5,
+            iterations:   50,
+            min_movement: 0.001
+        }
+    }
+}
+
+// Inclusive range of grid coordinates
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GridArea {
+    pub min_x: usize,
+    pub min_z: usize,
+    pub max_x: usize,
+    pub max_z: usize
+}
+
+impl GridArea {
+    pub fn full(cols: usize, rows: usize) -> Self {
+        GridArea {
+            min_x: 0,
+            min_z: 0,
+            max_x: cols.saturating_sub(1),
+            max_z: rows.saturating_sub(1)
+        }
+    }
+
+    pub fn contains(&self, x: usize, z: usize) -> bool {
+        return x >= self.min_x && x <= self.max_x && z >= self.min_z && z <= self.max_z;
+    }
+
+    pub fn from_indices(indices: impl Iterator<Item = usize>, cols: usize) -> Option<Self> {
+        let mut area: Option<GridArea> = None;
+        for index in indices {
+            let x = index % cols;
+            let z = index / cols;
+            area = Some(match area {
+                None => GridArea{min_x: x, min_z: z, max_x: x, max_z: z},
+                Some(a) => GridArea{
+                    min_x: a.min_x.min(x),
+                    min_z: a.min_z.min(z),
+                    max_x: a.max_x.max(x),
+                    max_z: a.max_z.max(z)
+                }
+            });
+        }
+        return area;
+    }
+}
+
+// Small deterministic generator so the erosion result only depends on the seed
+struct Rng(u64);
+impl Rng {
+    fn new(seed: u32) -> Self {
+        Rng(seed as u64 ^ 0x9E37_79B9_7F4A_7C15)
+    }
+    fn next_f32(&mut self) -> f32 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^= z >> 31;
+        return (z >> 40) as f32 / (1u64 << 24) as f32;
+    }
+}
+
+// Height and gradient at a point inside the grid, bilinearly interpolated
+fn height_and_gradient(heights: &[f32], cols: usize, x: f32, z: f32) -> (f32, f32, f32) {
+    let cx = x as usize;
+    let cz = z as usize;
+    let ox = x - cx as f32;
+    let oz = z - cz as f32;
+    let index = cz*cols + cx;
+    let h00 = heights[index];
+    let h10 = heights[index + 1];
+    let h01 = heights[index + cols];
+    let h11 = heights[index + cols + 1];
+    let gx = (h10 - h00)*(1.0 - oz) + (h11 - h01)*oz;
+    let gz = (h01 - h00)*(1.0 - ox) + (h11 - h10)*ox;
+    let h = h00*(1.0 - ox)*(1.0 - oz) + h10*ox*(1.0 - oz) + h01*(1.0 - ox)*oz + h11*ox*oz;
+    return (h, gx, gz);
+}
+
+fn cell_weights(cols: usize, x: f32, z: f32) -> [(usize, f32); 4] {
+    let cx = x as usize;
+    let cz = z as usize;
+    let ox = x - cx as f32;
+    let oz = z - cz as f32;
+    let index = cz*cols + cx;
+    return [
+        (index, (1.0 - ox)*(1.0 - oz)),
+        (index + 1, ox*(1.0 - oz)),
+        (index + cols, (1.0 - ox)*oz),
+        (index + cols + 1, ox*oz)
+    ];
+}
+
+// Droplet based erosion over a cols x rows height grid, droplets start inside area
+pub fn hydraulic_erosion(
+    heights: &mut [f32],
+    cols:    usize,
+    rows:    usize,
+    params:  &HydraulicErosion,
+    area:    Option<GridArea>
+){
+    if cols < 2 || rows < 2 || heights.len() < cols*rows {
+        return;
+    }
+    let area = area.unwrap_or(GridArea::full(cols, rows));
+    let max_x = (cols - 1) as f32;
+    let max_z = (rows - 1) as f32;
+    let mut rng = Rng::new(params.seed);
+
+    for _ in 0..params.iterations {
+        let mut x = area.min_x as f32 + rng.next_f32()*(area.max_x - area.min_x) as f32;
+        let mut z = area.min_z as f32 + rng.next_f32()*(area.max_z - area.min_z) as f32;
+        let mut dir_x: f32 = 0.0;
+        let mut dir_z: f32 = 0.0;
+        let mut speed: f32 = params.initial_speed;
+        let mut water: f32 = params.initial_water;
+        let mut sediment: f32 = 0.0;
+
+        for _ in 0..params.max_lifetime {
+            if x < 0.0 || z < 0.0 || x >= max_x || z >= max_z {
+                break;
+            }
+            let weights = cell_weights(cols, x, z);
+            let (height, gx, gz) = height_and_gradient(heights, cols, x, z);
+
+            dir_x = dir_x*params.inertia - gx*(1.0 - params.inertia);
+            dir_z = dir_z*params.inertia - gz*(1.0 - params.inertia);
+            let len = (dir_x*dir_x + dir_z*dir_z).sqrt();
+            if len <= f32::EPSILON {
+                break;
+            }
+            dir_x /= len;
+            dir_z /= len;
+            x += dir_x;
+            z += dir_z;
+            if x < 0.0 || z < 0.0 || x >= max_x || z >= max_z {
+                break;
+            }
+
+            let (new_height, _, _) = height_and_gradient(heights, cols, x, z);
+            let delta_height = new_height - height;
+            let capacity = (-delta_height*speed*water*params.capacity).max(params.min_capacity);
+
+            if sediment > capacity || delta_height > 0.0 {
+                let amount = if delta_height > 0.0 {
+                    delta_height.min(sediment)
+                } else {
+                    (sediment - capacity)*params.deposition
+                };
+                sediment -= amount;
+                for (index, weight) in weights.iter(){
+                    heights[*index] += amount*weight;
+                }
+            } else {
+                let amount = ((capacity - sediment)*params.erosion).min(-delta_height);
+                for (index, weight) in weights.iter(){
+                    heights[*index] -= amount*weight;
+                }
+                sediment += amount;
+            }
+
+            speed = (speed*speed + delta_height*params.gravity).max(0.0).sqrt();
+            water *= 1.0 - params.evaporation;
+        }
+    }
+}
+
+// Material a grid vertex sheds to its lower neighbours in one thermal iteration
+struct ThermalOutflow {
+    index: usize,
+    amount: f32,
+    excess: [(usize, f32); 4],
+    count: usize,
+    total_excess: f32
+}
+
+fn thermal_outflow(
+    heights:    &[f32],
+    cols:       usize,
+    rows:       usize,
+    area:       &GridArea,
+    max_diff:   Vec2,
+    rate:       f32,
+    index:      usize
+) -> Option<ThermalOutflow> {
+    let x = index % cols;
+    let z = index / cols;
+    let height = heights[index];
+    let mut excess: [(usize, f32); 4] = [(0, 0.0); 4];
+    let mut count: usize = 0;
+    let mut total_excess: f32 = 0.0;
+    let mut max_excess: f32 = 0.0;
+
+    let neighbours: [(bool, usize, usize, f32); 4] = [
+        (x > 0, x.wrapping_sub(1), z, max_diff.x),
+        (x + 1 < cols, x + 1, z, max_diff.x),
+        (z > 0, x, z.wrapping_sub(1), max_diff.y),
+        (z + 1 < rows, x, z + 1, max_diff.y)
+    ];
+    for (valid, nx, nz, max_diff) in neighbours {
+        if !valid || !area.contains(nx, nz) {
+            continue;
+        }
+        let neighbour = nz*cols + nx;
+        let diff = height - heights[neighbour] - max_diff;
+        if diff > 0.0 {
+            excess[count] = (neighbour, diff);
+            count += 1;
+            total_excess += diff;
+            max_excess = max_excess.max(diff);
+        }
+    }
+    if count == 0 {
+        return None;
+    }
+    return Some(ThermalOutflow{index, amount: rate*max_excess*0.5, excess, count, total_excess});
+}
+
+// Moves material from slopes steeper than the talus angle to lower neighbours.
+// Returns the amount of material moved in each iteration that ran.
+// Outflows only read the previous iteration and are summed in grid order, so parallel and serial results are identical.
+pub fn thermal_erosion(
+    heights:   &mut [f32],
+    cols:      usize,
+    rows:      usize,
+    cell_size: Vec2,
+    params:    &ThermalErosion,
+    area:      Option<GridArea>,
+    parallel:  bool
+) -> Vec<f32> {
+    let mut moved_per_iteration: Vec<f32> = Vec::new();
+    if cols < 2 || rows < 2 || heights.len() < cols*rows {
+        return moved_per_iteration;
+    }
+    let area = area.unwrap_or(GridArea::full(cols, rows));
+    let talus = params.talus_angle.to_radians().tan();
+    let max_diff = cell_size*talus;
+    let rate = params.rate.clamp(0.0, 1.0);
+    let area_rows: Vec<usize> = (area.min_z..=area.max_z).collect();
+    let rows_per_chunk: usize = (PARALLEL_CHUNK_SIZE/(area.max_x - area.min_x + 1)).max(1);
+    let mut deltas: Vec<f32> = vec![0.0; heights.len()];
+
+    for _ in 0..params.iterations {
+        deltas.iter_mut().for_each(|d| *d = 0.0);
+        let mut moved: f32 = 0.0;
+
+        let current: &[f32] = heights;
+        let row_outflows = |row_chunk: &[usize]| -> Vec<ThermalOutflow> {
+            return row_chunk
+                .iter()
+                .flat_map(|z| (area.min_x..=area.max_x).filter_map(move |x| thermal_outflow(current, cols, rows, &area, max_diff, rate, z*cols + x)))
+                .collect();
+        };
+        let outflows: Vec<ThermalOutflow> = if parallel {
+            let task_pool = ComputeTaskPool::get_or_init(TaskPool::default);
+            area_rows
+                .par_chunk_map(task_pool, rows_per_chunk, |_, row_chunk| row_outflows(row_chunk))
+                .into_iter()
+                .flatten()
+                .collect()
+        } else {
+            row_outflows(&area_rows)
+        };
+
+        for outflow in outflows.iter(){
+            deltas[outflow.index] -= outflow.amount;
+            for (neighbour, diff) in outflow.excess[..outflow.count].iter(){
+                deltas[*neighbour] += outflow.amount*diff/outflow.total_excess;
+            }
+            moved += outflow.amount;
+        }
+
+        for (height, delta) in heights.iter_mut().zip(deltas.iter()){
+            *height += delta;
+        }
+        moved_per_iteration.push(moved);
+        if moved < params.min_movement {
+            break;
+        }
+    }
+    return moved_per_iteration;
+}
+
+// Erodes the area around the given (index, weight) pairs of a cols x rows grid and blends the result back by weight
+pub fn erode_vertices(
+    heights:   &mut [f32],
+    cols:      usize,
+    rows:      usize,
+    cell_size: Vec2,
+    indices:   &[(usize, f32)],
+    erosion:   &Erosion
+){
+    let Some(area) = GridArea::from_indices(indices.iter().map(|(index, _)| *index), cols) else {return;};
+    let mut eroded: Vec<f32> = heights.to_vec();
+    erosion.run_grid(&mut eroded, cols, rows, cell_size, Some(area));
+    for (index, weight) in indices.iter(){
+        if *index >= heights.len() {
+            continue;
+        }
+        heights[*index] += (eroded[*index] - heights[*index])*weight.clamp(0.0, 1.0);
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Erosion {
+    Hydraulic(HydraulicErosion),
+    Thermal(ThermalErosion)
+}
+
+impl Erosion {
+    // Returns the material moved per iteration, empty for hydraulic erosion.
+    // Droplets erode what the previous ones left, so hydraulic erosion always runs serially.
+    pub fn run(&self, heights: &mut [f32], plane: &PlaneToEdit, area: Option<GridArea>) -> Vec<f32> {
+        let (cols, rows) = plane.grid_size();
+        return self.run_grid(heights, cols, rows, plane.cell_size(), area);
+    }
+
+    // Same as run on any cols x rows grid, chunked terrains erode their global grid with it
+    pub fn run_grid(&self, heights: &mut [f32], cols: usize, rows: usize, cell_size: Vec2, area: Option<GridArea>) -> Vec<f32> {
+        match self {
+            Erosion::Hydraulic(params) => {
+                hydraulic_erosion(heights, cols, rows, params, area);
+                return Vec::new();
+            }
+            Erosion::Thermal(params) => {
+                let area_size = area.map(|area| (area.max_x - area.min_x + 1)*(area.max_z - area.min_z + 1)).unwrap_or(cols*rows);
+                return thermal_erosion(heights, cols, rows, cell_size, params, area, area_size >= PARALLEL_THRESHOLD);
+            }
+        }
+    }
+}
+
+// Runs the erosion over the whole plane
+#[derive(Event)]
+pub struct ErodePlane {
+    pub plane_entity: Entity,
+    pub erosion: Erosion
+}
+
+#[derive(Event, Debug)]
+pub struct PlaneEroded {
+    pub plane_entity: Entity,
+    pub moved: Vec<f32>
+}
+
+pub(crate) fn erode_plane(
+    trigger:      On<ErodePlane>,
+    mut commands: Commands,
+    mut planes:   Query<(&PlaneToEdit, &mut TerrainHeightfield)>,
+    mut history:  Option<ResMut<BrushHistory>>
+){
+    let Ok((plane, mut heightfield)) = planes.get_mut(trigger.plane_entity) else {return;};
+    if heightfield.len() != plane.vertex_count() {
+        return;
+    }
+
+    let mut heights: Vec<f32> = heightfield.heights.clone();
+    let moved = trigger.erosion.run(&mut heights, plane, None);
+
+    // Inside a brush stroke the erosion joins it, otherwise it is a stroke of its own
+    let own_stroke: bool = history.as_ref().is_some_and(|history| !history.is_recording());
+    if own_stroke && let Some(history) = history.as_mut() {
+        history.begin_stroke();
+    }
+    for (index, height) in heights.into_iter().enumerate(){
+        if let Some(history) = history.as_mut() {
+            history.record(VertexSnapshot::new(trigger.plane_entity, index, &heightfield));
+        }
+        heightfield.set_height(index, height);
+    }
+    if own_stroke && let Some(history) = history.as_mut() {
+        history.end_stroke();
+    }
+    commands.trigger(PlaneEroded{plane_entity: trigger.plane_entity, moved});
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COLS: usize = 48;
+    const ROWS: usize = 40;
+
+    // Ridge along z with a spike, steep enough for both erosions
+    fn ridge() -> Vec<f32> {
+        let mut heights: Vec<f32> = (0..COLS*ROWS)
+            .map(|i| {
+                let (x, z) = ((i % COLS) as f32, (i / COLS) as f32);
+                return 12.0 - (x - COLS as f32*0.5).abs()*0.8 + (z*0.3).sin();
+            })
+            .collect();
+        heights[20*COLS + 24] += 15.0;
+        return heights;
+    }
+
+    fn max_slope(heights: &[f32]) -> f32 {
+        let mut slope: f32 = 0.0;
+        for z in 0..ROWS {
+            for x in 0..COLS {
+                let height = heights[z*COLS + x];
+                if x + 1 < COLS {slope = slope.max((height - heights[z*COLS + x + 1]).abs());}
+                if z + 1 < ROWS {slope = slope.max((height - heights[(z + 1)*COLS + x]).abs());}
+            }
+        }
+        return slope;
+    }
+
+    #[test]
+    fn hydraulic_same_seed_same_result() {
+        let params = HydraulicErosion{iterations: 2000, seed: 11, ..HydraulicErosion::new()};
+        let mut first = ridge();
+        let mut second = ridge();
+        hydraulic_erosion(&mut first, COLS, ROWS, &params, None);
+        hydraulic_erosion(&mut second, COLS, ROWS, &params, None);
+        assert_ne!(first, ridge());
+        assert_eq!(first, second);
+        assert!(first.iter().all(|height| height.is_finite()));
+
+        let mut other_seed = ridge();
+        hydraulic_erosion(&mut other_seed, COLS, ROWS, &HydraulicErosion{seed: 12, ..params}, None);
+        assert_ne!(first, other_seed);
+    }
+
+    #[test]
+    fn hydraulic_stays_in_area() {
+        let area = GridArea{min_x: 10, min_z: 10, max_x: 20, max_z: 20};
+        let params = HydraulicErosion{iterations: 500, max_lifetime: 1, ..HydraulicErosion::new()};
+        let mut heights = ridge();
+        hydraulic_erosion(&mut heights, COLS, ROWS, &params, Some(area));
+        // One step droplets only touch the cells around their start
+        for (index, (before, after)) in ridge().iter().zip(heights.iter()).enumerate(){
+            let (x, z) = (index % COLS, index / COLS);
+            if !(9..=22).contains(&x) || !(9..=22).contains(&z) {
+                assert_eq!(before, after);
+            }
+        }
+    }
+
+    #[test]
+    fn thermal_conserves_mass_and_lowers_slopes() {
+        let params = ThermalErosion{iterations: 200, talus_angle: 30.0, ..ThermalErosion::new()};
+        let mut heights = ridge();
+        let moved = thermal_erosion(&mut heights, COLS, ROWS, Vec2::ONE, &params, None, false);
+        assert!(!moved.is_empty() && moved.iter().all(|amount| amount.is_finite()));
+        assert!(heights.iter().all(|height| height.is_finite()));
+
+        let before: f64 = ridge().iter().map(|height| *height as f64).sum();
+        let after: f64 = heights.iter().map(|height| *height as f64).sum();
+        assert!((before - after).abs() < 1e-2, "{before} != {after}");
+        assert!(max_slope(&heights) < max_slope(&ridge()));
+        // Material moves less as the slopes settle
+        assert!(moved.last().unwrap() < moved.first().unwrap());
+
+        let mut again = ridge();
+        thermal_erosion(&mut again, COLS, ROWS, Vec2::ONE, &params, None, false);
+        assert_eq!(heights, again);
+    }
+
+    #[test]
+    fn parallel_thermal_matches_serial() {
+        let (cols, rows) = (128, 128);
+        let heights: Vec<f32> = (0..cols*rows).map(|i| ((i % cols) as f32*0.4).sin()*6.0 + (i / cols) as f32*0.2).collect();
+        let params = ThermalErosion{iterations: 10, ..ThermalErosion::new()};
+        let area = Some(GridArea{min_x: 3, min_z: 5, max_x: 120, max_z: 110});
+
+        let mut serial = heights.clone();
+        let mut parallel = heights.clone();
+        let serial_moved = thermal_erosion(&mut serial, cols, rows, Vec2::ONE, &params, area, false);
+        let parallel_moved = thermal_erosion(&mut parallel, cols, rows, Vec2::ONE, &params, area, true);
+        assert_ne!(serial, heights);
+        assert_eq!(serial, parallel);
+        assert_eq!(serial_moved, parallel_moved);
+    }
+}
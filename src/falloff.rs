@@ -0,0 +1,119 @@
+use serde::{Serialize, Deserialize};
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Falloff {
+    #[default]
+    Constant,
+    Linear,
+    Smoothstep,
+    Gaussian,
+    Curve(Vec<[f32;2]>) // [normalized distance, weight] points
+}
+
+impl Falloff {
+    pub fn sample(&self, distance: f32) -> f32 {
+        let t: f32 = if distance.is_nan() {0.0} else {distance.clamp(0.0, 1.0)};
+        match self {
+            // Full strength up to the radius, the radius itself is the edge
+            Falloff::Constant => {return if t < 1.0 {1.0} else {0.0};}
+            Falloff::Linear => {return 1.0 - t;}
+            Falloff::Smoothstep => {
+                let s = 1.0 - t;
+                return s*s*(3.0 - 2.0*s);
+            }
+            Falloff::Gaussian => {
+                // sigma = 1/3, shifted and rescaled so the ~1% left at the edge goes to 0
+                let edge = libm::expf(-4.5);
+                return (libm::expf(-4.5*t*t) - edge)/(1.0 - edge);
+            }
+            Falloff::Curve(points) => {
+                return sample_curve(points, t);
+            }
+        }
+    }
+}
+
+fn sample_curve(points: &[[f32;2]], t: f32) -> f32 {
+    if points.is_empty(){
+        return 1.0;
+    }
+    let mut sorted: Vec<[f32;2]> = points.to_vec();
+    sorted.sort_by(|a, b| a[0].total_cmp(&b[0]));
+
+    let first = sorted[0];
+    let last = sorted[sorted.len()-1];
+    if t <= first[0] {
+        return first[1];
+    }
+    if t >= last[0] {
+        return last[1];
+    }
+    for pair in sorted.windows(2){
+        let [a, b] = [pair[0], pair[1]];
+        if t >= a[0] && t <= b[0] {
+            let span = b[0] - a[0];
+            if span <= f32::EPSILON {
+                return b[1];
+            }
+            return a[1] + (b[1] - a[1])*(t - a[0])/span;
+        }
+    }
+    return last[1];
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn variants() -> Vec<Falloff> {
+        return vec![
+            Falloff::Constant,
+            Falloff::Linear,
+            Falloff::Smoothstep,
+            Falloff::Gaussian,
+            Falloff::Curve(vec![[0.0, 1.0], [0.4, 0.9], [1.0, 0.0]])
+        ];
+    }
+
+    #[test]
+    fn full_at_centre_and_zero_at_radius() {
+        for falloff in variants() {
+            assert_eq!(falloff.sample(0.0), 1.0, "{falloff:?}");
+            assert!(falloff.sample(1.0).abs() < 1e-6, "{falloff:?}");
+            // Out of range distances are clamped
+            assert_eq!(falloff.sample(-0.5), 1.0, "{falloff:?}");
+            assert_eq!(falloff.sample(f32::NAN), 1.0, "{falloff:?}");
+            assert!(falloff.sample(3.0).abs() < 1e-6, "{falloff:?}");
+        }
+    }
+
+    #[test]
+    fn monotonic_in_between() {
+        for falloff in variants() {
+            let mut previous = falloff.sample(0.0);
+            for step in 1..=100 {
+                let weight = falloff.sample(step as f32/100.0);
+                assert!(weight <= previous, "{falloff:?} rises at {step}: {previous} -> {weight}");
+                assert!((0.0..=1.0).contains(&weight), "{falloff:?} at {step}: {weight}");
+                previous = weight;
+            }
+        }
+    }
+
+    #[test]
+    fn curve_samples() {
+        // Unsorted points, flat before the first and after the last one
+        let curve = Falloff::Curve(vec![[0.8, 0.2], [0.2, 1.0], [0.5, 0.5]]);
+        let expected = [(0.0, 1.0), (0.2, 1.0), (0.35, 0.75), (0.5, 0.5), (0.65, 0.35), (0.8, 0.2), (1.0, 0.2)];
+        for (t, weight) in expected {
+            assert!((curve.sample(t) - weight).abs() < 1e-5, "{t}: {} != {weight}", curve.sample(t));
+        }
+        assert_eq!(Falloff::Curve(Vec::new()).sample(0.5), 1.0);
+        assert_eq!(Falloff::Curve(vec![[0.5, 0.3]]).sample(0.9), 0.3);
+        // Points at the same distance step from one value to the other
+        let step = Falloff::Curve(vec![[0.0, 1.0], [0.5, 1.0], [0.5, 0.0], [1.0, 0.0]]);
+        assert_eq!(step.sample(0.25), 1.0);
+        assert_eq!(step.sample(0.75), 0.0);
+    }
+}
@@ -0,0 +1,264 @@
+use bevy::prelude::*;
+use std::ops::Range;
+
+use crate::planes::{PlaneToEdit, plane_distance};
+use crate::erosion::GridArea;
+use crate::vertex::extract_mesh_data;
+
+// Heights and colors of a plane grid in plane space, index = z*cols + x as in plane_mesh.
+// Brushes edit this component, the mesh and the optional vertex markers follow it.
+#[derive(Component, Clone, Debug)]
+pub struct TerrainHeightfield {
+    pub width: f32,
+    pub height: f32,
+    pub cols: usize,
+    pub rows: usize,
+    pub heights: Vec<f32>,
+    pub colors: Vec<[f32;4]>,
+    selected: Vec<bool>,
+    selected_indices: Vec<usize>,
+    dirty: Vec<bool>,
+    dirty_indices: Vec<usize>,
+    selection_changes: Vec<usize>
+}
+
+impl TerrainHeightfield {
+    pub fn new(plane: &PlaneToEdit) -> Self {
+        let (cols, rows) = plane.grid_size();
+        return TerrainHeightfield::from_data(
+            plane,
+            vec![0.0; cols*rows],
+            vec![[1.0, 1.0, 1.0, 1.0]; cols*rows]
+        );
+    }
+
+    fn from_data(plane: &PlaneToEdit, heights: Vec<f32>, colors: Vec<[f32;4]>) -> Self {
+        let (cols, rows) = plane.grid_size();
+        TerrainHeightfield {
+            width: plane.width,
+            height: plane.height,
+            cols,
+            rows,
+            selected: vec![false; heights.len()],
+            selected_indices: Vec::new(),
+            dirty: vec![false; heights.len()],
+            dirty_indices: Vec::new(),
+            selection_changes: Vec::new(),
+            heights,
+            colors
+        }
+    }
+
+    pub fn from_mesh(plane: &PlaneToEdit, mesh: &Mesh) -> Option<Self> {
+        let positions = mesh.attribute(Mesh::ATTRIBUTE_POSITION)?.as_float3()?;
+        if positions.len() < plane.vertex_count() {
+            return None;
+        }
+        let (mut v_pos, mut v_clr) = extract_mesh_data(mesh);
+        if v_clr.len() != v_pos.len() {
+            return None;
+        }
+        v_pos.truncate(plane.vertex_count());
+        v_clr.truncate(plane.vertex_count());
+        let heights: Vec<f32> = v_pos.iter().map(|pos| pos[1]).collect();
+        return Some(TerrainHeightfield::from_data(plane, heights, v_clr));
+    }
+
+    pub fn len(&self) -> usize {
+        return self.heights.len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.heights.is_empty();
+    }
+
+    pub fn index(&self, x: usize, z: usize) -> usize {
+        return z*self.cols + x;
+    }
+
+    pub fn coords(&self, index: usize) -> (usize, usize) {
+        return (index % self.cols, index / self.cols);
+    }
+
+    // Same formula as the Plane3d mesh builder so positions match the mesh exactly
+    pub fn local_position(&self, index: usize) -> Vec3 {
+        let (x, z) = self.coords(index);
+        let tx = x as f32 / (self.cols - 1) as f32;
+        let tz = z as f32 / (self.rows - 1) as f32;
+        return Vec3::new((-0.5 + tx)*self.width, self.heights[index], (-0.5 + tz)*self.height);
+    }
+
+    pub fn set_height(&mut self, index: usize, height: f32) {
+        if index >= self.heights.len() || self.heights[index] == height {
+            return;
+        }
+        self.heights[index] = height;
+        self.mark_dirty(index);
+    }
+
+    pub fn set_color(&mut self, index: usize, clr: [f32;4]) {
+        if index >= self.colors.len() || self.colors[index] == clr {
+            return;
+        }
+        self.colors[index] = clr;
+        self.mark_dirty(index);
+    }
+
+    pub fn mark_dirty(&mut self, index: usize) {
+        if index < self.dirty.len() && !self.dirty[index] {
+            self.dirty[index] = true;
+            self.dirty_indices.push(index);
+        }
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        return !self.dirty_indices.is_empty() || !self.selection_changes.is_empty();
+    }
+
+    pub fn is_vertex_dirty(&self, index: usize) -> bool {
+        return self.dirty.get(index).copied().unwrap_or(false);
+    }
+
+    // Indices changed and not taken yet, in the order they were changed
+    pub fn dirty_indices(&self) -> &[usize] {
+        return &self.dirty_indices;
+    }
+
+    // Indices changed since the last call
+    pub fn take_dirty(&mut self) -> Vec<usize> {
+        for index in self.dirty_indices.iter(){
+            self.dirty[*index] = false;
+        }
+        return std::mem::take(&mut self.dirty_indices);
+    }
+
+    // Changed indices since the last call merged into sorted contiguous ranges
+    pub fn take_dirty_ranges(&mut self) -> Vec<Range<usize>> {
+        let mut dirty = self.take_dirty();
+        dirty.sort_unstable();
+        let mut ranges: Vec<Range<usize>> = Vec::new();
+        for index in dirty.into_iter(){
+            match ranges.last_mut() {
+                Some(range) if range.end == index => {range.end = index + 1;}
+                _ => {ranges.push(index..index + 1);}
+            }
+        }
+        return ranges;
+    }
+
+    pub fn is_selected(&self, index: usize) -> bool {
+        return self.selected.get(index).copied().unwrap_or(false);
+    }
+
+    // Returns true when the vertex was not selected yet
+    pub fn select(&mut self, index: usize) -> bool {
+        if index >= self.selected.len() || self.selected[index] {
+            return false;
+        }
+        self.selected[index] = true;
+        self.selected_indices.push(index);
+        self.selection_changes.push(index);
+        return true;
+    }
+
+    pub fn deselect(&mut self, index: usize) {
+        if index >= self.selected.len() || !self.selected[index] {
+            return;
+        }
+        self.selected[index] = false;
+        self.selected_indices.retain(|selected| *selected != index);
+        self.selection_changes.push(index);
+    }
+
+    // Deselects every selected vertex whose plane space position fails the test
+    pub fn retain_selection(&mut self, mut keep: impl FnMut(Vec3) -> bool) {
+        let selected_indices = std::mem::take(&mut self.selected_indices);
+        for index in selected_indices.into_iter(){
+            if keep(self.local_position(index)) {
+                self.selected_indices.push(index);
+            } else {
+                self.selected[index] = false;
+                self.selection_changes.push(index);
+            }
+        }
+    }
+
+    pub fn clear_selection(&mut self) {
+        self.retain_selection(|_| false);
+    }
+
+    // Grid cells that can be within reach of a plane space location, reach is in world units
+    // as measured by plane_distance. Only these have to be visited by a brush.
+    pub fn area_within(&self, plane_transform: &GlobalTransform, local_loc: Vec3, reach: f32) -> Option<GridArea> {
+        if self.cols < 2 || self.rows < 2 || self.width <= 0.0 || self.height <= 0.0 || reach < 0.0 {
+            return None;
+        }
+        // The world circle is an ellipse in plane space, take its bounding box
+        let matrix = plane_transform.affine().matrix3;
+        let gxx = matrix.x_axis.length_squared();
+        let gzz = matrix.z_axis.length_squared();
+        let gxz = matrix.x_axis.dot(matrix.z_axis);
+        let det = gxx*gzz - gxz*gxz;
+        if det <= f32::EPSILON {
+            return Some(GridArea::full(self.cols, self.rows));
+        }
+        let half_x = reach*(gzz/det).sqrt();
+        let half_z = reach*(gxx/det).sqrt();
+
+        let cell_x = self.width/(self.cols - 1) as f32;
+        let cell_z = self.height/(self.rows - 1) as f32;
+        // Rounded outwards, the distance test decides for the vertices on the border
+        let min_x = ((local_loc.x - half_x + self.width*0.5)/cell_x).floor();
+        let max_x = ((local_loc.x + half_x + self.width*0.5)/cell_x).ceil();
+        let min_z = ((local_loc.z - half_z + self.height*0.5)/cell_z).floor();
+        let max_z = ((local_loc.z + half_z + self.height*0.5)/cell_z).ceil();
+        if !(min_x <= max_x && min_z <= max_z) || max_x < 0.0 || max_z < 0.0 {
+            return None;
+        }
+        if min_x > (self.cols - 1) as f32 || min_z > (self.rows - 1) as f32 {
+            return None;
+        }
+        return Some(GridArea {
+            min_x: min_x.max(0.0) as usize,
+            min_z: min_z.max(0.0) as usize,
+            max_x: (max_x as usize).min(self.cols - 1),
+            max_z: (max_z as usize).min(self.rows - 1)
+        });
+    }
+
+    // Indices within reach of a plane space location, visiting only the cells of area_within
+    pub fn indices_within(&self, plane_transform: &GlobalTransform, local_loc: Vec3, reach: f32) -> Vec<(usize, f32)> {
+        let mut indices: Vec<(usize, f32)> = Vec::new();
+        let Some(area) = self.area_within(plane_transform, local_loc, reach) else {return indices;};
+        for z in area.min_z..=area.max_z {
+            for x in area.min_x..=area.max_x {
+                let index = self.index(x, z);
+                let distance = plane_distance(plane_transform, local_loc, self.local_position(index));
+                if distance <= reach {
+                    indices.push((index, distance));
+                }
+            }
+        }
+        return indices;
+    }
+
+    // Indices whose selection toggled since the last call
+    pub fn take_selection_changes(&mut self) -> Vec<usize> {
+        let mut changes = std::mem::take(&mut self.selection_changes);
+        changes.sort_unstable();
+        changes.dedup();
+        return changes;
+    }
+}
+
+pub(crate) fn init_heightfields(
+    mut commands: Commands,
+    planes:       Query<(Entity, &PlaneToEdit, &Mesh3d), Without<TerrainHeightfield>>,
+    meshes:       Res<Assets<Mesh>>
+){
+    for (plane_entity, plane, mesh3d) in planes.iter(){
+        let Some(mesh) = meshes.get(&mesh3d.0) else {continue;};
+        let Some(heightfield) = TerrainHeightfield::from_mesh(plane, mesh) else {continue;};
+        commands.entity(plane_entity).insert(heightfield);
+    }
+}
@@ -0,0 +1,358 @@
+use bevy::prelude::*;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+
+use crate::planes::PlaneToEdit;
+use crate::save::TerrainName;
+use crate::vertex::SpawnVertices;
+use crate::normals::{TerrainShading, update_normals};
+
+const MIN_HEIGHT_KEY: &str = "min_height";
+const MAX_HEIGHT_KEY: &str = "max_height";
+
+// Heights in world units laid out row by row (z), matching the plane_mesh vertex order
+#[derive(Clone, Debug, PartialEq)]
+pub struct Heightmap {
+    pub width: u32,
+    pub height: u32,
+    pub data: Vec<f32>
+}
+
+impl Heightmap {
+    pub fn new(width: u32, height: u32, data: Vec<f32>) -> Self {
+        Heightmap { width, height, data }
+    }
+
+    pub fn from_plane(plane: &PlaneToEdit, mesh: &Mesh) -> Option<Heightmap> {
+        let positions = mesh.attribute(Mesh::ATTRIBUTE_POSITION)?.as_float3()?;
+        let (cols, rows) = plane.grid_size();
+        if positions.len() < cols*rows {
+            return None;
+        }
+        let data: Vec<f32> = positions[..cols*rows].iter().map(|pos| pos[1]).collect();
+        return Some(Heightmap::new(cols as u32, rows as u32, data));
+    }
+
+    pub fn min_max(&self) -> (f32, f32) {
+        let mut min: f32 = f32::MAX;
+        let mut max: f32 = f32::MIN;
+        for h in self.data.iter(){
+            min = min.min(*h);
+            max = max.max(*h);
+        }
+        if self.data.is_empty() {
+            return (0.0, 0.0);
+        }
+        return (min, max);
+    }
+
+    fn get(&self, x: u32, y: u32) -> f32 {
+        let x = x.min(self.width - 1);
+        let y = y.min(self.height - 1);
+        return self.data[(y*self.width + x) as usize];
+    }
+
+    // Bilinear sample with u, v in 0..1
+    pub fn sample(&self, u: f32, v: f32) -> f32 {
+        if self.width == 0 || self.height == 0 {
+            return 0.0;
+        }
+        let fx = u.clamp(0.0, 1.0) * (self.width - 1) as f32;
+        let fy = v.clamp(0.0, 1.0) * (self.height - 1) as f32;
+        let x0 = fx.floor() as u32;
+        let y0 = fy.floor() as u32;
+        let tx = fx - x0 as f32;
+        let ty = fy - y0 as f32;
+        let top = self.get(x0, y0) + (self.get(x0 + 1, y0) - self.get(x0, y0))*tx;
+        let bottom = self.get(x0, y0 + 1) + (self.get(x0 + 1, y0 + 1) - self.get(x0, y0 + 1))*tx;
+        return top + (bottom - top)*ty;
+    }
+
+    pub fn resample(&self, width: u32, height: u32) -> Heightmap {
+        if width == self.width && height == self.height {
+            return self.clone();
+        }
+        let mut data: Vec<f32> = Vec::with_capacity((width*height) as usize);
+        for y in 0..height {
+            for x in 0..width {
+                let u = if width > 1 {x as f32 / (width - 1) as f32} else {0.0};
+                let v = if height > 1 {y as f32 / (height - 1) as f32} else {0.0};
+                data.push(self.sample(u, v));
+            }
+        }
+        return Heightmap::new(width, height, data);
+    }
+
+    // 16-bit grayscale PNG, the height range is kept in tEXt chunks
+    pub fn save_png(&self, path: &Path) -> std::io::Result<()> {
+        if let Some(directory) = path.parent() {
+            std::fs::create_dir_all(directory)?;
+        }
+        let (min, max) = self.min_max();
+        let range = max - min;
+        let mut bytes: Vec<u8> = Vec::with_capacity(self.data.len()*2);
+        for h in self.data.iter(){
+            let normalized = if range > 0.0 {(h - min)/range} else {0.0};
+            let value = (normalized.clamp(0.0, 1.0) * u16::MAX as f32).round() as u16;
+            bytes.extend_from_slice(&value.to_be_bytes());
+        }
+
+        let file = File::create(path)?;
+        let mut encoder = png::Encoder::new(BufWriter::new(file), self.width, self.height);
+        encoder.set_color(png::ColorType::Grayscale);
+        encoder.set_depth(png::BitDepth::Sixteen);
+        encoder.add_text_chunk(MIN_HEIGHT_KEY.to_string(), min.to_string()).map_err(to_io_error)?;
+        encoder.add_text_chunk(MAX_HEIGHT_KEY.to_string(), max.to_string()).map_err(to_io_error)?;
+        let mut writer = encoder.write_header().map_err(to_io_error)?;
+        writer.write_image_data(&bytes).map_err(to_io_error)?;
+        writer.finish().map_err(to_io_error)?;
+        return Ok(());
+    }
+
+    // Without height metadata the image values are mapped to 0..1
+    pub fn load_png(path: &Path) -> std::io::Result<Heightmap> {
+        let file = File::open(path)?;
+        let mut decoder = png::Decoder::new(BufReader::new(file));
+        decoder.set_transformations(png::Transformations::EXPAND);
+        let mut reader = decoder.read_info().map_err(to_io_error)?;
+
+        let mut min: f32 = 0.0;
+        let mut max: f32 = 1.0;
+        for chunk in reader.info().uncompressed_latin1_text.iter(){
+            if chunk.keyword == MIN_HEIGHT_KEY {
+                min = chunk.text.parse().unwrap_or(min);
+            } else if chunk.keyword == MAX_HEIGHT_KEY {
+                max = chunk.text.parse().unwrap_or(max);
+            }
+        }
+
+        let Some(buffer_size) = reader.output_buffer_size() else {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "heightmap image is too large"));
+        };
+        let mut buf = vec![0; buffer_size];
+        let info = reader.next_frame(&mut buf).map_err(to_io_error)?;
+        let channels: usize = match info.color_type {
+            png::ColorType::Grayscale | png::ColorType::Indexed => 1,
+            png::ColorType::GrayscaleAlpha => 2,
+            png::ColorType::Rgb => 3,
+            png::ColorType::Rgba => 4
+        };
+        let sixteen_bit: bool = info.bit_depth == png::BitDepth::Sixteen;
+        let bytes_per_sample: usize = if sixteen_bit {2} else {1};
+
+        let mut data: Vec<f32> = Vec::with_capacity((info.width*info.height) as usize);
+        for y in 0..info.height as usize {
+            for x in 0..info.width as usize {
+                let offset = y*info.line_size + x*channels*bytes_per_sample;
+                let normalized: f32 = if sixteen_bit {
+                    u16::from_be_bytes([buf[offset], buf[offset + 1]]) as f32 / u16::MAX as f32
+                } else {
+                    buf[offset] as f32 / u8::MAX as f32
+                };
+                data.push(min + normalized*(max - min));
+            }
+        }
+        return Ok(Heightmap::new(info.width, info.height, data));
+    }
+}
+
+fn to_io_error(e: impl std::error::Error + Send + Sync + 'static) -> std::io::Error {
+    return std::io::Error::new(std::io::ErrorKind::InvalidData, e);
+}
+
+pub fn export_heightmap(plane: &PlaneToEdit, mesh: &Mesh, path: &Path) -> std::io::Result<()> {
+    let Some(heightmap) = Heightmap::from_plane(plane, mesh) else {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "mesh does not match the plane grid"));
+    };
+    return heightmap.save_png(path);
+}
+
+pub fn heightmap_plane_mesh(
+    width: f32,
+    height: f32,
+    subdivisions: u32,
+    heightmap: &Heightmap,
+    meshes: &mut ResMut<Assets<Mesh>>
+) -> impl Bundle {
+    let plane = PlaneToEdit::new(width, height, subdivisions);
+    let (cols, rows) = plane.grid_size();
+    let heights = heightmap.resample(cols as u32, rows as u32);
+
+    let mut mesh = Plane3d::default().mesh().size(width, height).subdivisions(subdivisions).build();
+    if let Some(positions) = mesh.attribute(Mesh::ATTRIBUTE_POSITION).and_then(|attr| attr.as_float3()) {
+        let positions: Vec<[f32; 3]> = positions
+            .iter()
+            .zip(heights.data.iter())
+            .map(|(pos, h)| [pos[0], *h, pos[2]])
+            .collect();
+        mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, positions);
+    }
+    update_normals(&mut mesh, &plane, TerrainShading::Smooth, None);
+    (
+        Mesh3d(meshes.add(mesh)),
+        plane
+    )
+}
+
+// Spawns an editable plane from a heightmap PNG, resampled to the plane grid
+#[derive(Event)]
+pub struct LoadHeightmap {
+    pub path: PathBuf,
+    pub width: f32,
+    pub height: f32,
+    pub subdivisions: u32,
+    pub transform: Transform
+}
+impl LoadHeightmap {
+    pub fn new(path: impl Into<PathBuf>, width: f32, height: f32, subdivisions: u32) -> Self {
+        LoadHeightmap {
+            path: path.into(),
+            width,
+            height,
+            subdivisions,
+            transform: Transform::default()
+        }
+    }
+}
+
+#[derive(Event, Debug)]
+pub struct HeightmapLoaded {
+    pub path: PathBuf,
+    pub result: Result<Entity, String>
+}
+
+pub(crate) fn load_heightmap(
+    trigger:       On<LoadHeightmap>,
+    mut commands:  Commands,
+    mut meshes:    ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>
+){
+    let path = trigger.path.clone();
+    let heightmap = match Heightmap::load_png(&path) {
+        Ok(heightmap) => heightmap,
+        Err(e) => {
+            error!("failed to load heightmap from {:?}: {}", path, e);
+            commands.trigger(HeightmapLoaded{path, result: Err(e.to_string())});
+            return;
+        }
+    };
+    let name: String = path.file_stem().map(|stem| stem.to_string_lossy().to_string()).unwrap_or_default();
+
+    let plane_entity = commands.spawn((
+        heightmap_plane_mesh(trigger.width, trigger.height, trigger.subdivisions, &heightmap, &mut meshes),
+        TerrainName(name),
+        MeshMaterial3d(materials.add(StandardMaterial::from_color(Color::WHITE))),
+        trigger.transform
+    )).id();
+
+    commands.trigger(SpawnVertices{plane_entity});
+    commands.trigger(HeightmapLoaded{path, result: Ok(plane_entity)});
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ramp(width: u32, height: u32) -> Heightmap {
+        let data: Vec<f32> = (0..width*height)
+            .map(|i| (i % width) as f32 + 2.0*(i / width) as f32)
+            .collect();
+        return Heightmap::new(width, height, data);
+    }
+
+    fn plane_heights(world: &World, plane_entity: Entity) -> Vec<[f32;3]> {
+        let mesh3d = world.get::<Mesh3d>(plane_entity).unwrap();
+        let mesh = world.resource::<Assets<Mesh>>().get(&mesh3d.0).unwrap();
+        return mesh.attribute(Mesh::ATTRIBUTE_POSITION).unwrap().as_float3().unwrap().to_vec();
+    }
+
+    #[test]
+    fn png_round_trip() {
+        let data: Vec<f32> = (0..12*7).map(|i| (i as f32*0.37).sin().abs()*5.5).collect();
+        let mut heightmap = Heightmap::new(12, 7, data);
+        heightmap.data[0] = 0.0;
+        heightmap.data[1] = 5.5;
+
+        let directory = std::env::temp_dir().join(format!("terrain_heightmap_{}", std::process::id()));
+        let path = directory.join("hills.png");
+        heightmap.save_png(&path).unwrap();
+        let loaded = Heightmap::load_png(&path).unwrap();
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        assert_eq!((loaded.width, loaded.height), (12, 7));
+        assert_eq!(loaded.min_max(), (0.0, 5.5));
+        // 16 bits over a 5.5 range, half a step is about 4.2e-5
+        for (saved, loaded) in heightmap.data.iter().zip(loaded.data.iter()){
+            assert!((saved - loaded).abs() < 5e-5, "{saved} != {loaded}");
+        }
+    }
+
+    #[test]
+    fn resample_between_grid_sizes() {
+        // Bilinear sampling keeps a linear ramp exact in both directions
+        let heightmap = ramp(3, 2);
+        let up = heightmap.resample(5, 3);
+        assert_eq!((up.width, up.height), (5, 3));
+        for (i, h) in up.data.iter().enumerate(){
+            let (x, y) = ((i % 5) as f32*0.5, (i / 5) as f32*0.5);
+            assert!((h - (x + 2.0*y)).abs() < 1e-5, "({x}, {y}): {h}");
+        }
+        let down = up.resample(3, 2);
+        assert_eq!(down, heightmap);
+        assert_eq!(heightmap.resample(3, 2), heightmap);
+    }
+
+    #[test]
+    fn plane_mesh_follows_the_heightmap() {
+        use bevy::ecs::system::RunSystemOnce;
+
+        let mut world = World::new();
+        world.init_resource::<Assets<Mesh>>();
+        let heightmap = ramp(2, 2);
+        let plane_entity = world.run_system_once(move |mut commands: Commands, mut meshes: ResMut<Assets<Mesh>>| {
+            return commands.spawn(heightmap_plane_mesh(6.0, 3.0, 2, &heightmap, &mut meshes)).id();
+        }).unwrap();
+
+        // Image x runs along plane x and image rows along plane z, from the -x -z corner
+        let positions = plane_heights(&world, plane_entity);
+        assert_eq!(positions.len(), 16);
+        for pos in positions.iter(){
+            let (u, v) = ((pos[0] + 3.0)/6.0, (pos[2] + 1.5)/3.0);
+            assert!((pos[1] - (u + 2.0*v)).abs() < 1e-5, "{pos:?}");
+        }
+    }
+
+    #[test]
+    fn load_event_spawns_the_plane() {
+        let directory = std::env::temp_dir().join(format!("terrain_heightmap_load_{}", std::process::id()));
+        let path = directory.join("ramp.png");
+        ramp(4, 4).save_png(&path).unwrap();
+
+        let mut world = World::new();
+        world.init_resource::<Assets<Mesh>>();
+        world.init_resource::<Assets<StandardMaterial>>();
+        world.add_observer(load_heightmap);
+        world.add_observer(|trigger: On<HeightmapLoaded>, mut commands: Commands| {
+            commands.insert_resource(LastLoaded(trigger.result.clone()));
+        });
+        world.trigger(LoadHeightmap::new(&path, 4.0, 4.0, 2));
+        world.flush();
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        let plane_entity = world.resource::<LastLoaded>().0.clone().unwrap();
+        assert_eq!(world.get::<TerrainName>(plane_entity).unwrap().0, "ramp");
+        assert_eq!(world.get::<PlaneToEdit>(plane_entity).unwrap().subdivisions, 2);
+        for pos in plane_heights(&world, plane_entity).iter(){
+            let (x, z) = (pos[0] + 2.0, pos[2] + 2.0);
+            assert!((pos[1] - (x + 2.0*z)*0.75).abs() < 1e-3, "{pos:?}");
+        }
+
+        world.trigger(LoadHeightmap::new(directory.join("missing.png"), 4.0, 4.0, 2));
+        world.flush();
+        assert!(world.resource::<LastLoaded>().0.is_err());
+    }
+
+    #[derive(Resource)]
+    struct LastLoaded(Result<Entity, String>);
+}
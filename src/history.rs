@@ -0,0 +1,241 @@
+use bevy::prelude::*;
+use bevy_enhanced_input::prelude::*;
+use std::collections::{HashSet, VecDeque};
+
+use crate::heightfield::TerrainHeightfield;
+
+#[derive(Clone, Copy, Debug)]
+pub struct VertexSnapshot {
+    pub plane_entity: Entity,
+    pub index: usize,
+    pub height: f32,
+    pub clr: [f32;4]
+}
+impl VertexSnapshot {
+    pub fn new(plane_entity: Entity, index: usize, heightfield: &TerrainHeightfield) -> Self {
+        VertexSnapshot {
+            plane_entity,
+            index,
+            height: heightfield.heights[index],
+            clr: heightfield.colors[index]
+        }
+    }
+}
+
+#[derive(Clone, Default, Debug)]
+pub struct Stroke {
+    pub vertices: Vec<VertexSnapshot>
+}
+
+#[derive(Resource)]
+pub struct BrushHistory {
+    pub capacity: usize,
+    undo: VecDeque<Stroke>,
+    redo: Vec<Stroke>,
+    current: Option<(Stroke, HashSet<(Entity, usize)>)>
+}
+
+impl Default for BrushHistory {
+    fn default() -> Self {
+        BrushHistory::new(100)
+    }
+}
+
+impl BrushHistory {
+    pub fn new(capacity: usize) -> Self {
+        BrushHistory {
+            capacity,
+            undo: VecDeque::new(),
+            redo: Vec::new(),
+            current: None
+        }
+    }
+
+    // Whether a stroke was begun and not ended yet
+    pub fn is_recording(&self) -> bool {
+        return self.current.is_some();
+    }
+
+    pub fn begin_stroke(&mut self) {
+        self.current = Some((Stroke::default(), HashSet::new()));
+    }
+
+    // Only the first snapshot of a vertex within a stroke is kept
+    pub fn record(&mut self, snapshot: VertexSnapshot) {
+        let Some((stroke, recorded)) = &mut self.current else {return;};
+        if recorded.insert((snapshot.plane_entity, snapshot.index)) {
+            stroke.vertices.push(snapshot);
+        }
+    }
+
+    pub fn end_stroke(&mut self) {
+        let Some((stroke, _)) = self.current.take() else {return;};
+        if stroke.vertices.is_empty() {
+            return;
+        }
+        self.push_undo(stroke);
+        self.redo.clear();
+    }
+
+    pub fn can_undo(&self) -> bool {
+        return !self.undo.is_empty();
+    }
+
+    pub fn can_redo(&self) -> bool {
+        return !self.redo.is_empty();
+    }
+
+    pub fn clear(&mut self) {
+        self.undo.clear();
+        self.redo.clear();
+        self.current = None;
+    }
+
+    fn push_undo(&mut self, stroke: Stroke) {
+        self.undo.push_back(stroke);
+        while self.undo.len() > self.capacity {
+            self.undo.pop_front();
+        }
+    }
+}
+
+// Restores the last recorded stroke, apps without the vertex controller trigger it themselves
+#[derive(Event)]
+pub struct UndoBrushStroke;
+
+// Reapplies the last undone stroke
+#[derive(Event)]
+pub struct RedoBrushStroke;
+
+#[derive(InputAction)]
+#[action_output(bool)]
+pub struct UndoStroke;
+
+#[derive(InputAction)]
+#[action_output(bool)]
+pub struct RedoStroke;
+
+pub(crate) fn undo_stroke(
+    _trigger:     On<Fire<UndoStroke>>,
+    mut commands: Commands
+){
+    commands.trigger(UndoBrushStroke);
+}
+
+pub(crate) fn redo_stroke(
+    _trigger:     On<Fire<RedoStroke>>,
+    mut commands: Commands
+){
+    commands.trigger(RedoBrushStroke);
+}
+
+pub(crate) fn undo_brush_stroke(
+    _trigger:     On<UndoBrushStroke>,
+    mut history:  ResMut<BrushHistory>,
+    mut planes:   Query<&mut TerrainHeightfield>
+){
+    let Some(stroke) = history.undo.pop_back() else {return;};
+    let redo = restore_stroke(&stroke, &mut planes);
+    history.redo.push(redo);
+}
+
+pub(crate) fn redo_brush_stroke(
+    _trigger:     On<RedoBrushStroke>,
+    mut history:  ResMut<BrushHistory>,
+    mut planes:   Query<&mut TerrainHeightfield>
+){
+    let Some(stroke) = history.redo.pop() else {return;};
+    let undo = restore_stroke(&stroke, &mut planes);
+    history.push_undo(undo);
+}
+
+// Writes the stroke snapshots back and returns the snapshots of the values they replaced
+fn restore_stroke(
+    stroke: &Stroke,
+    planes: &mut Query<&mut TerrainHeightfield>
+) -> Stroke {
+    let mut replaced = Stroke::default();
+    for snapshot in stroke.vertices.iter(){
+        let Ok(mut heightfield) = planes.get_mut(snapshot.plane_entity) else {continue;};
+        if snapshot.index >= heightfield.len() {
+            continue;
+        }
+        replaced.vertices.push(VertexSnapshot::new(snapshot.plane_entity, snapshot.index, &heightfield));
+        heightfield.set_height(snapshot.index, snapshot.height);
+        heightfield.set_color(snapshot.index, snapshot.clr);
+    }
+    return replaced;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy_pg_editor_tools::prelude::BrushType;
+    use crate::planes::PlaneToEdit;
+    use crate::terrain_brushes::{TerrainHeightBrush, HeightBrushType};
+    use crate::erosion::{ErodePlane, Erosion, ThermalErosion, erode_plane};
+
+    fn history_world() -> (World, Entity) {
+        let mut world = World::new();
+        world.init_resource::<BrushHistory>();
+        world.add_observer(undo_brush_stroke);
+        world.add_observer(redo_brush_stroke);
+        world.add_observer(erode_plane);
+        let plane = PlaneToEdit::new(4.0, 4.0, 3);
+        let heightfield = TerrainHeightfield::new(&plane);
+        let plane_entity = world.spawn((plane, heightfield, GlobalTransform::default())).id();
+        return (world, plane_entity);
+    }
+
+    fn heights(world: &World, plane_entity: Entity) -> Vec<f32> {
+        return world.get::<TerrainHeightfield>(plane_entity).unwrap().heights.clone();
+    }
+
+    #[test]
+    fn undo_and_redo_events() {
+        let (mut world, plane_entity) = history_world();
+        let mut brush = TerrainHeightBrush::new(HeightBrushType::Value(1.0));
+        brush.started(&mut world);
+        brush.apply(&mut world, Vec3::ZERO, 10.0);
+        brush.done(&mut world);
+        let raised = heights(&world, plane_entity);
+        assert!(raised.iter().all(|height| *height == 1.0));
+
+        world.trigger(UndoBrushStroke);
+        assert!(heights(&world, plane_entity).iter().all(|height| *height == 0.0));
+        assert!(!world.resource::<BrushHistory>().can_undo());
+
+        world.trigger(RedoBrushStroke);
+        assert_eq!(heights(&world, plane_entity), raised);
+        assert!(world.resource::<BrushHistory>().can_undo());
+        assert!(!world.resource::<BrushHistory>().can_redo());
+    }
+
+    #[test]
+    fn erosion_joins_the_current_stroke() {
+        let (mut world, plane_entity) = history_world();
+        let mut brush = TerrainHeightBrush::new(HeightBrushType::Value(3.0));
+        brush.started(&mut world);
+        brush.apply(&mut world, Vec3::ZERO, 0.5);
+        world.trigger(ErodePlane{plane_entity, erosion: Erosion::Thermal(ThermalErosion::new())});
+        assert!(world.resource::<BrushHistory>().is_recording());
+        brush.apply(&mut world, Vec3::new(1.5, 0.0, 1.5), 0.5);
+        brush.done(&mut world);
+
+        // One undo removes both the brush edits and the erosion
+        world.trigger(UndoBrushStroke);
+        assert!(heights(&world, plane_entity).iter().all(|height| *height == 0.0));
+        assert!(!world.resource::<BrushHistory>().can_undo());
+
+        // Outside a stroke the erosion is undone on its own
+        let mut spike = TerrainHeightBrush::new(HeightBrushType::Value(3.0));
+        spike.started(&mut world);
+        spike.apply(&mut world, Vec3::ZERO, 0.5);
+        spike.done(&mut world);
+        let spiked = heights(&world, plane_entity);
+        world.trigger(ErodePlane{plane_entity, erosion: Erosion::Thermal(ThermalErosion::new())});
+        assert_ne!(heights(&world, plane_entity), spiked);
+        world.trigger(UndoBrushStroke);
+        assert_eq!(heights(&world, plane_entity), spiked);
+    }
+}
@@ -1,11 +1,32 @@
+pub mod chunks;
+pub mod erosion;
+pub mod falloff;
+pub mod heightfield;
+pub mod heightmap;
+pub mod history;
 pub mod noises;
+pub mod noise_expr;
+pub mod normals;
 pub mod planes;
+pub mod presets;
+pub mod save;
 pub mod vertex;
 pub mod terrain_brushes;
 
 pub mod prelude {
-    pub use crate::planes::{PlaneToEdit, plane_mesh};
-    pub use crate::vertex::{SpawnVertices, SelectedVertex, PlaneVertex, TerrainEditorVertexPlugin, TerrainVertexController, VertexRefs, terrain_vertex_controller};
-    pub use crate::terrain_brushes::{TerrainHeightBrush, TerrainColorBrush, HeightBrushType, ColorBrushType};
-    pub use crate::noises::{NoiseType, Noise};
+    pub use crate::planes::{PlaneToEdit, TerrainRayHit, plane_mesh, plane_distance, world_height, local_height, grid_neighbours};
+    pub use crate::vertex::{SpawnVertices, SelectedVertex, PlaneVertex, VertexMarkers, TerrainEditorVertexPlugin, TerrainVertexController, VertexRefs, terrain_vertex_controller, write_heightfield};
+    pub use crate::terrain_brushes::{TerrainHeightBrush, TerrainColorBrush, HeightBrushType, ColorBrushType, FlattenTarget};
+    pub use crate::noises::{NoiseType, Noise, DomainWarp, WorleyDistance, WorleyReturnType};
+    pub use crate::noise_expr::NoiseExpr;
+    pub use crate::falloff::Falloff;
+    pub use crate::erosion::{Erosion, ErodePlane, PlaneEroded, GridArea, HydraulicErosion, ThermalErosion, hydraulic_erosion, thermal_erosion};
+    pub use crate::heightfield::TerrainHeightfield;
+    pub use crate::chunks::{ChunkLayout, ChunkedTerrain, TerrainChunk, spawn_chunked_terrain};
+    pub use crate::heightmap::{Heightmap, LoadHeightmap, HeightmapLoaded, export_heightmap, heightmap_plane_mesh};
+    pub use crate::history::{BrushHistory, Stroke, VertexSnapshot, UndoBrushStroke, RedoBrushStroke, UndoStroke, RedoStroke};
+    pub use crate::save::{SaveTerrain, TerrainSaved, TerrainSaveSettings, TerrainName, LoadTerrain, TerrainLoaded, save_mesh_to_file, load_plane_from_file,
+        SaveChunkedTerrain, ChunkedTerrainSaved, LoadChunkedTerrain, ChunkedTerrainLoaded, ChunkManifest, save_chunks_to_file, load_chunks_from_file};
+    pub use crate::normals::{TerrainShading, grid_normals, set_mesh_layout, update_normals};
+    pub use crate::presets::{BrushPresets, BrushPresetsLoader, BrushPresetLibrary, ActivateBrushPreset, TerrainBrushPresetPlugin};
 }
\ No newline at end of file
@@ -0,0 +1,192 @@
+use bevy::prelude::Vec3;
+use serde::{Serialize, Deserialize};
+
+use crate::noises::{Noise, DomainWarp};
+
+// Tree of noises combined into a single height function
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NoiseExpr {
+    Noise(Noise),
+    Constant(f32),
+    Add(Vec<NoiseExpr>),
+    Multiply(Vec<NoiseExpr>),
+    Min(Box<NoiseExpr>, Box<NoiseExpr>),
+    Max(Box<NoiseExpr>, Box<NoiseExpr>),
+    // control in -1..1 blends from a to b
+    Blend{a: Box<NoiseExpr>, b: Box<NoiseExpr>, control: Box<NoiseExpr>},
+    // a below threshold, b above, smoothly blended within +-falloff of the threshold
+    Select{a: Box<NoiseExpr>, b: Box<NoiseExpr>, control: Box<NoiseExpr>, threshold: f32, falloff: f32},
+    Abs(Box<NoiseExpr>),
+    Clamp{expr: Box<NoiseExpr>, min: f32, max: f32},
+    // keeps the sign so negative values don't turn into NaN
+    Power{expr: Box<NoiseExpr>, exponent: f32},
+    Remap{expr: Box<NoiseExpr>, from: [f32;2], to: [f32;2]},
+    Warp{expr: Box<NoiseExpr>, warp: DomainWarp}
+}
+
+impl NoiseExpr {
+    pub fn eval(&self, x: f32, z: f32) -> f32 {
+        let loc = Vec3::new(x, 0.0, z);
+        return self.sample(loc, loc);
+    }
+
+    pub fn sample(&self, world_loc: Vec3, local_loc: Vec3) -> f32 {
+        match self {
+            NoiseExpr::Noise(noise) => {return noise.sample(world_loc, local_loc);}
+            NoiseExpr::Constant(value) => {return *value;}
+            NoiseExpr::Add(exprs) => {
+                return exprs.iter().map(|expr| expr.sample(world_loc, local_loc)).sum();
+            }
+            NoiseExpr::Multiply(exprs) => {
+                return exprs.iter().map(|expr| expr.sample(world_loc, local_loc)).product();
+            }
+            NoiseExpr::Min(a, b) => {
+                return a.sample(world_loc, local_loc).min(b.sample(world_loc, local_loc));
+            }
+            NoiseExpr::Max(a, b) => {
+                return a.sample(world_loc, local_loc).max(b.sample(world_loc, local_loc));
+            }
+            NoiseExpr::Blend{a, b, control} => {
+                let t = ((control.sample(world_loc, local_loc) + 1.0)*0.5).clamp(0.0, 1.0);
+                let a = a.sample(world_loc, local_loc);
+                let b = b.sample(world_loc, local_loc);
+                return a + (b - a)*t;
+            }
+            NoiseExpr::Select{a, b, control, threshold, falloff} => {
+                let control = control.sample(world_loc, local_loc);
+                let falloff = falloff.max(0.0);
+                if control <= threshold - falloff {
+                    return a.sample(world_loc, local_loc);
+                }
+                if control >= threshold + falloff {
+                    return b.sample(world_loc, local_loc);
+                }
+                let t = (control - (threshold - falloff))/(2.0*falloff);
+                let t = t*t*(3.0 - 2.0*t);
+                let a = a.sample(world_loc, local_loc);
+                let b = b.sample(world_loc, local_loc);
+                return a + (b - a)*t;
+            }
+            NoiseExpr::Abs(expr) => {return expr.sample(world_loc, local_loc).abs();}
+            NoiseExpr::Clamp{expr, min, max} => {
+                return expr.sample(world_loc, local_loc).clamp(*min, max.max(*min));
+            }
+            NoiseExpr::Power{expr, exponent} => {
+                let value = expr.sample(world_loc, local_loc);
+                return value.signum()*value.abs().powf(*exponent);
+            }
+            NoiseExpr::Remap{expr, from, to} => {
+                let value = expr.sample(world_loc, local_loc);
+                let span = from[1] - from[0];
+                if span.abs() <= f32::EPSILON {
+                    return to[0];
+                }
+                return to[0] + (value - from[0])/span*(to[1] - to[0]);
+            }
+            NoiseExpr::Warp{expr, warp} => {
+                return expr.sample(warp_loc(warp, world_loc), warp_loc(warp, local_loc));
+            }
+        }
+    }
+}
+
+fn warp_loc(warp: &DomainWarp, loc: Vec3) -> Vec3 {
+    let (x, z) = warp.warp(loc.x as f64, loc.z as f64);
+    return Vec3::new(x as f32, loc.y, z as f32);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn constant(value: f32) -> Box<NoiseExpr> {
+        return Box::new(NoiseExpr::Constant(value));
+    }
+
+    fn assert_close(value: f32, expected: f32) {
+        assert!((value - expected).abs() < 1e-5, "{value} != {expected}");
+    }
+
+    #[test]
+    fn arithmetic_values() {
+        let add = NoiseExpr::Add(vec![NoiseExpr::Constant(1.5), NoiseExpr::Constant(-0.25), NoiseExpr::Constant(2.0)]);
+        assert_eq!(add.eval(3.0, 4.0), 3.25);
+        let multiply = NoiseExpr::Multiply(vec![NoiseExpr::Constant(1.5), NoiseExpr::Constant(-2.0), NoiseExpr::Constant(0.5)]);
+        assert_eq!(multiply.eval(3.0, 4.0), -1.5);
+        assert_eq!(NoiseExpr::Add(Vec::new()).eval(0.0, 0.0), 0.0);
+        assert_eq!(NoiseExpr::Multiply(Vec::new()).eval(0.0, 0.0), 1.0);
+        assert_eq!(NoiseExpr::Min(constant(0.3), constant(-0.7)).eval(0.0, 0.0), -0.7);
+        assert_eq!(NoiseExpr::Max(constant(0.3), constant(-0.7)).eval(0.0, 0.0), 0.3);
+        assert_eq!(NoiseExpr::Abs(constant(-0.7)).eval(0.0, 0.0), 0.7);
+    }
+
+    #[test]
+    fn blend_and_select_values() {
+        let blend = |control: f32| NoiseExpr::Blend{a: constant(2.0), b: constant(6.0), control: constant(control)}.eval(0.0, 0.0);
+        assert_eq!(blend(-1.0), 2.0);
+        assert_eq!(blend(0.0), 4.0);
+        assert_eq!(blend(0.5), 5.0);
+        assert_eq!(blend(1.0), 6.0);
+        assert_eq!(blend(3.0), 6.0);
+
+        let select = |control: f32, falloff: f32| NoiseExpr::Select{
+            a: constant(2.0),
+            b: constant(6.0),
+            control: constant(control),
+            threshold: 0.5,
+            falloff
+        }.eval(0.0, 0.0);
+        assert_eq!(select(0.2, 0.2), 2.0);
+        assert_close(select(0.5, 0.2), 4.0);
+        assert_eq!(select(0.8, 0.2), 6.0);
+        assert_eq!(select(0.49, 0.0), 2.0);
+        assert_eq!(select(0.51, 0.0), 6.0);
+        // Smoothstep within the falloff, a quarter of the way in gives 0.15625 of b - a
+        assert_close(select(0.4, 0.2), 2.625);
+    }
+
+    #[test]
+    fn shaping_values() {
+        let clamp = |value: f32, min: f32, max: f32| NoiseExpr::Clamp{expr: constant(value), min, max}.eval(0.0, 0.0);
+        assert_eq!(clamp(1.5, -1.0, 1.0), 1.0);
+        assert_eq!(clamp(-1.5, -1.0, 1.0), -1.0);
+        assert_eq!(clamp(0.25, -1.0, 1.0), 0.25);
+        assert_eq!(clamp(0.25, 1.0, -1.0), 1.0);
+
+        let power = |value: f32, exponent: f32| NoiseExpr::Power{expr: constant(value), exponent}.eval(0.0, 0.0);
+        assert_eq!(power(3.0, 2.0), 9.0);
+        assert_eq!(power(-3.0, 2.0), -9.0);
+        assert_close(power(0.25, 0.5), 0.5);
+        assert_close(power(-0.25, 0.5), -0.5);
+
+        let remap = |value: f32, from: [f32;2], to: [f32;2]| NoiseExpr::Remap{expr: constant(value), from, to}.eval(0.0, 0.0);
+        assert_close(remap(0.0, [-1.0, 1.0], [0.0, 10.0]), 5.0);
+        assert_close(remap(-1.0, [-1.0, 1.0], [0.0, 10.0]), 0.0);
+        assert_close(remap(2.0, [-1.0, 1.0], [0.0, 10.0]), 15.0);
+        assert_close(remap(0.5, [0.0, 1.0], [1.0, -1.0]), 0.0);
+        assert_eq!(remap(0.5, [1.0, 1.0], [3.0, 4.0]), 3.0);
+    }
+
+    #[test]
+    fn nested_values() {
+        // (|min(x, y)| clamped to 0..2) remapped from 0..2 to 10..20, blended halfway with 0
+        let expr = NoiseExpr::Blend{
+            a: constant(0.0),
+            b: Box::new(NoiseExpr::Remap{
+                expr: Box::new(NoiseExpr::Clamp{expr: Box::new(NoiseExpr::Abs(Box::new(NoiseExpr::Min(constant(-1.5), constant(4.0))))), min: 0.0, max: 2.0}),
+                from: [0.0, 2.0],
+                to: [10.0, 20.0]
+            }),
+            control: constant(0.0)
+        };
+        assert_close(expr.eval(0.0, 0.0), 8.75);
+
+        // A warp without noises leaves the location alone
+        let noise = Noise::new();
+        let warped = NoiseExpr::Warp{expr: Box::new(NoiseExpr::Noise(noise.clone())), warp: DomainWarp::new(Vec::new(), 2.0)};
+        for (x, z) in [(0.3, -1.2), (5.5, 2.25), (-7.0, 0.0)] {
+            assert_eq!(warped.eval(x, z), NoiseExpr::Noise(noise.clone()).eval(x, z));
+        }
+    }
+}
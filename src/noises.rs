@@ -1,17 +1,100 @@
 
-use noise::{NoiseFn, OpenSimplex, Perlin, PerlinSurflet, Simplex, SuperSimplex, Value, Worley, Fbm, Billow, BasicMulti, RidgedMulti, HybridMulti};
+use noise::{NoiseFn, MultiFractal, Seedable, OpenSimplex, Perlin, PerlinSurflet, Simplex, SuperSimplex, Value, Worley, Fbm, Billow, BasicMulti, RidgedMulti, HybridMulti};
+use noise::core::worley::{ReturnType, distance_functions};
 use std::slice::Iter;
+use std::cell::RefCell;
+use serde::{Serialize, Deserialize};
 use bevy::prelude::Vec3;
 
-#[derive(Clone, Debug)]
+const NOISE_CACHE_SIZE: usize = 32;
+
+// NoiseFunction is not Send (Worley holds an Rc), so built functions are cached per thread
+thread_local! {
+    static NOISE_CACHE: RefCell<Vec<(NoiseKey, NoiseFunction)>> = const {RefCell::new(Vec::new())};
+}
+
+// Shifts the z displacement lookup so x and z are not displaced by the same value
+const WARP_DECORRELATION: [f64;2] = [5.2, 1.3];
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DomainWarp {
+    pub noises: Vec<Noise>,
+    pub strength: f32,
+    #[serde(default = "default_warp_iterations")]
+    pub iterations: usize
+}
+
+// Warps saved before iterations existed warped once
+fn default_warp_iterations() -> usize {
+    return 1;
+}
+
+impl DomainWarp {
+    pub fn new(noises: Vec<Noise>, strength: f32) -> Self {
+        DomainWarp {
+            noises,
+            strength,
+            iterations: 1
+        }
+    }
+    // Each iteration displaces the original coordinates by the warp noises sampled at the previous result
+    pub fn warp(&self, x: f64, z: f64) -> (f64, f64) {
+        let mut wx: f64 = x;
+        let mut wz: f64 = z;
+        for _ in 0..self.iterations {
+            let mut dx: f32 = 0.0;
+            let mut dz: f32 = 0.0;
+            for noise in self.noises.iter(){
+                dx += noise.apply(Vec3::new(wx as f32, 0.0, wz as f32));
+                dz += noise.apply(Vec3::new((wx + WARP_DECORRELATION[0]) as f32, 0.0, (wz + WARP_DECORRELATION[1]) as f32));
+            }
+            wx = x + (dx*self.strength) as f64;
+            wz = z + (dz*self.strength) as f64;
+        }
+        return (wx, wz);
+    }
+}
+
+// Fields the NoiseFunction is built from
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct NoiseKey {
+    typ: NoiseType,
+    seed: u32,
+    octaves: usize,
+    freq: f64,
+    lacunarity: Option<f64>,
+    persistence: Option<f64>,
+    attenuation: Option<f64>,
+    worley_distance: WorleyDistance,
+    worley_return: WorleyReturnType
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Noise {
     pub typ: NoiseType,
     pub seed: u32,
     pub scale: f64,
     pub octaves: usize,
     pub freq: f64,
-    pub global: bool
+    pub global: bool, // sample in world space instead of plane local space
+    pub offset: [f64;2],
+    pub rotation: f64, // radians around y
+    pub scale_xz: [f64;2],
+    // None keeps the noise crate default for the fractal type
+    pub lacunarity: Option<f64>,
+    pub persistence: Option<f64>,
+    pub attenuation: Option<f64>, // RidgedMulti only
+    pub worley_distance: WorleyDistance,
+    pub worley_return: WorleyReturnType,
+    pub warp: Option<Box<DomainWarp>>
+}
+impl Default for Noise {
+    fn default() -> Self {
+        Noise::new()
+    }
 }
+
 impl Noise {
     pub fn new() -> Self {
         Noise { 
@@ -20,16 +103,86 @@ impl Noise {
             scale:      1.0, 
             octaves:    6, 
             freq:       1.0,
-            global:     false
+            global:     false,
+            offset:     [0.0, 0.0],
+            rotation:   0.0,
+            scale_xz:   [1.0, 1.0],
+            lacunarity:      None,
+            persistence:     None,
+            attenuation:     None,
+            worley_distance: WorleyDistance::Euclidean,
+            worley_return:   WorleyReturnType::Value,
+            warp:            None
+        }
+    }
+    fn key(&self) -> NoiseKey {
+        NoiseKey {
+            typ:     self.typ,
+            seed:    self.seed,
+            octaves: self.octaves,
+            freq:    self.freq,
+            lacunarity:      self.lacunarity,
+            persistence:     self.persistence,
+            attenuation:     self.attenuation,
+            worley_distance: self.worley_distance,
+            worley_return:   self.worley_return
         }
     }
     fn _set(&self) -> NoiseFunction {
-        let nfn = NoiseFunction::new(self.typ.clone(), self.seed, self.octaves, self.freq);
+        let nfn = NoiseFunction::new(&self.key());
         return nfn;
     }
+    // Builds the NoiseFunction only when no function with the same parameters is cached
+    fn with_function<R>(&self, f: impl FnOnce(&NoiseFunction) -> R) -> R {
+        let key = self.key();
+        return NOISE_CACHE.with(|cache| {
+            let mut cache = cache.borrow_mut();
+            let position = match cache.iter().position(|(cached_key, _)| *cached_key == key) {
+                Some(position) => position,
+                None => {
+                    if cache.len() >= NOISE_CACHE_SIZE {
+                        cache.remove(0);
+                    }
+                    cache.push((key, self._set()));
+                    cache.len() - 1
+                }
+            };
+            return f(&cache[position].1);
+        });
+    }
+    // Moves, rotates and stretches the sampling coordinates so the feature is placed at offset
+    fn transform(&self, loc: Vec3) -> (f64, f64) {
+        let x = loc.x as f64 - self.offset[0];
+        let z = loc.z as f64 - self.offset[1];
+        let (sin, cos) = (-self.rotation).sin_cos();
+        let rx = x*cos - z*sin;
+        let rz = x*sin + z*cos;
+        return (rx*self.scale_xz[0], rz*self.scale_xz[1]);
+    }
+    fn coordinates(&self, loc: Vec3) -> (f64, f64) {
+        let (x, z) = self.transform(loc);
+        match &self.warp {
+            Some(warp) => {return warp.warp(x, z);}
+            None => {return (x, z);}
+        }
+    }
     pub fn apply(&self, loc: Vec3) -> f32 {
+        let (x, z) = self.coordinates(loc);
+        let r = self.with_function(|noise| noise.apply(self.scale, x, z));
+        return r as f32;
+    }
+    // Picks world or plane local coordinates depending on the global flag
+    pub fn sample(&self, world_loc: Vec3, local_loc: Vec3) -> f32 {
+        if self.global {
+            return self.apply(world_loc);
+        }
+        return self.apply(local_loc);
+    }
+    // Rebuilds the NoiseFunction on every call, kept for comparison in benchmarks
+    pub fn apply_uncached(&self, loc: Vec3) -> f32 {
+        let (x, z) = self.coordinates(loc);
         let noise = self._set();
-        let r = noise.apply(self.scale, loc.x as f64, loc.z as f64);
+        let r = noise.apply(self.scale, x, z);
         return r as f32;
     }
 }
@@ -38,34 +191,61 @@ impl Noise {
 
 
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub enum NoiseType {
+    #[serde(rename = "perlin")]
     Perlin,
+    #[serde(rename = "perlin_surflet")]
     PerlinSurflet,
+    #[serde(rename = "open_simplex")]
     OpenSimplex,
+    #[serde(rename = "value")]
     Value,
+    #[serde(rename = "super_simplex")]
     SuperSimplex,
+    #[serde(rename = "worley")]
     Worley,
+    #[serde(rename = "simplex")]
     Simplex,
+    #[serde(rename = "fbm_perlin")]
     FBMPerlin, // Fractal
+    #[serde(rename = "basic_multi_perlin")]
     BMPerlin, //Basic Multi
+    #[serde(rename = "billow_perlin")]
     BPerlin,
+    #[serde(rename = "ridged_multi_perlin")]
     RMPerlin, // RidgedMultiPerlin
+    #[serde(rename = "hybrid_multi_perlin")]
     HMPerlin, //Hybrid Multi perlin
+    #[serde(rename = "fbm_perlin_surflet")]
     FBMPerlinSurflet,
+    #[serde(rename = "basic_multi_perlin_surflet")]
     BMPerlinSurflet,
+    #[serde(rename = "billow_perlin_surflet")]
     BPerlinSurflet,
+    #[serde(rename = "ridged_multi_perlin_surflet")]
     RMPerlinSurflet,
+    #[serde(rename = "hybrid_multi_perlin_surflet")]
     HMPerlinSurflet,
+    #[serde(rename = "fbm_value")]
     FBMValue,
+    #[serde(rename = "basic_multi_value")]
     BMValue,
+    #[serde(rename = "billow_value")]
     BValue,
+    #[serde(rename = "ridged_multi_value")]
     RMValue,
+    #[serde(rename = "hybrid_multi_value")]
     HMValue,
+    #[serde(rename = "fbm_super_simplex")]
     FBMSS,
+    #[serde(rename = "basic_multi_super_simplex")]
     BMSS,
+    #[serde(rename = "billow_super_simplex")]
     BSS,
+    #[serde(rename = "ridged_multi_super_simplex")]
     RMSS,
+    #[serde(rename = "hybrid_multi_super_simplex")]
     HMSS 
 
 }
@@ -174,142 +354,97 @@ impl NoiseFunction {
 
  
 
-    fn new(noise: NoiseType, seed: u32, octaves: usize, freq: f64) -> Self {
+    fn new(key: &NoiseKey) -> Self {
+        let seed = key.seed;
         let nfn: NoiseFunction;
-        match noise {
-            NoiseType::Perlin =>        {nfn = NoiseFunction::Perlin(Perlin::new(seed))}
-            NoiseType::PerlinSurflet => {nfn = NoiseFunction::PerlinSurflet(PerlinSurflet::new(seed))}
-            NoiseType::Value =>         {nfn = NoiseFunction::Value(Value::new(seed))}
-            NoiseType::OpenSimplex =>   {nfn = NoiseFunction::OpenSimplex(OpenSimplex::new(seed))}
-            NoiseType::SuperSimplex =>  {nfn = NoiseFunction::SuperSimplex(SuperSimplex::new(seed))}
-            NoiseType::Worley =>        {nfn = NoiseFunction::Worley(Worley::new(seed))}
-            NoiseType::Simplex =>       {nfn = NoiseFunction::Simplex(Simplex::new(seed))}
-            NoiseType::FBMPerlin =>     {
-                let mut noise_fn: Fbm<Perlin> = Fbm::new(seed);
-                noise_fn.octaves = octaves;
-                noise_fn.frequency = freq;
-                nfn = NoiseFunction::FBMPerlin(noise_fn);
-            }
-            NoiseType::BMPerlin => {
-                let mut noise_fn: BasicMulti<Perlin> = BasicMulti::new(seed);
-                noise_fn.octaves = octaves;
-                noise_fn.frequency = freq;
-                nfn = NoiseFunction::BMPerlin(noise_fn);
-            }
-            NoiseType::BPerlin => {
-                let mut noise_fn: Billow<Perlin> = Billow::new(seed);
-                noise_fn.octaves = octaves;
-                noise_fn.frequency = freq;
-                nfn = NoiseFunction::BPerlin(noise_fn);
-            }
-            NoiseType::RMPerlin => {
-                let mut noise_fn: RidgedMulti<Perlin> = RidgedMulti::new(seed);
-                noise_fn.octaves = octaves;
-                noise_fn.frequency = freq;
-                nfn = NoiseFunction::RMPerlin(noise_fn);
-            }
-            NoiseType::HMPerlin => {
-                let mut noise_fn: HybridMulti<Perlin> = HybridMulti::new(seed);
-                noise_fn.octaves = octaves;
-                noise_fn.frequency = freq;
-                nfn =  NoiseFunction::HMPerlin(noise_fn);
-            }
-            NoiseType::FBMPerlinSurflet =>     {
-                let mut noise_fn: Fbm<PerlinSurflet> = Fbm::new(seed);
-                noise_fn.octaves = octaves;
-                noise_fn.frequency = freq;
-                nfn =  NoiseFunction::FBMPerlinSurflet(noise_fn);
-            }
-            NoiseType::BMPerlinSurflet => {
-                let mut noise_fn: BasicMulti<PerlinSurflet> = BasicMulti::new(seed);
-                noise_fn.octaves = octaves;
-                noise_fn.frequency = freq;
-                nfn =  NoiseFunction::BMPerlinSurflet(noise_fn);
-            }
-            NoiseType::BPerlinSurflet => {
-                let mut noise_fn: Billow<PerlinSurflet> = Billow::new(seed);
-                noise_fn.octaves = octaves;
-                noise_fn.frequency = freq;
-                nfn =  NoiseFunction::BPerlinSurflet(noise_fn);
-            }
-            NoiseType::RMPerlinSurflet => {
-                let mut noise_fn: RidgedMulti<PerlinSurflet> = RidgedMulti::new(seed);
-                noise_fn.octaves = octaves;
-                noise_fn.frequency = freq;
-                nfn =  NoiseFunction::RMPerlinSurflet(noise_fn);
-            }
-            NoiseType::HMPerlinSurflet => {
-                let mut noise_fn: HybridMulti<PerlinSurflet> = HybridMulti::new(seed);
-                noise_fn.octaves = octaves;
-                noise_fn.frequency = freq;
-                nfn =  NoiseFunction::HMPerlinSurflet(noise_fn);
-            }
-            NoiseType::FBMValue =>     {
-                let mut noise_fn: Fbm<Value> = Fbm::new(seed);
-                noise_fn.octaves = octaves;
-                noise_fn.frequency = freq;
-                nfn =  NoiseFunction::FBMValue(noise_fn);
-            }
-            NoiseType::BMValue => {
-                let mut noise_fn: BasicMulti<Value> = BasicMulti::new(seed);
-                noise_fn.octaves = octaves;
-                noise_fn.frequency = freq;
-                nfn =  NoiseFunction::BMValue(noise_fn);
-            }
-            NoiseType::BValue => {
-                let mut noise_fn: Billow<Value> = Billow::new(seed);
-                noise_fn.octaves = octaves;
-                noise_fn.frequency = freq;
-                nfn =  NoiseFunction::BValue(noise_fn);
-            }
-            NoiseType::RMValue => {
-                let mut noise_fn: RidgedMulti<Value> = RidgedMulti::new(seed);
-                noise_fn.octaves = octaves;
-                noise_fn.frequency = freq;
-                nfn =  NoiseFunction::RMValue(noise_fn);
-            }
-            NoiseType::HMValue => {
-                let mut noise_fn: HybridMulti<Value> = HybridMulti::new(seed);
-                noise_fn.octaves = octaves;
-                noise_fn.frequency = freq;
-                nfn =  NoiseFunction::HMValue(noise_fn);
-            }
-            NoiseType::FBMSS =>     {
-                let mut noise_fn: Fbm<SuperSimplex> = Fbm::new(seed);
-                noise_fn.octaves = octaves;
-                noise_fn.frequency = freq;
-                nfn =  NoiseFunction::FBMSS(noise_fn);
-            }
-            NoiseType::BMSS => {
-                let mut noise_fn: BasicMulti<SuperSimplex> = BasicMulti::new(seed);
-                noise_fn.octaves = octaves;
-                noise_fn.frequency = freq;
-                nfn =  NoiseFunction::BMSS(noise_fn);
-            }
-            NoiseType::BSS => {
-                let mut noise_fn: Billow<SuperSimplex> = Billow::new(seed);
-                noise_fn.octaves = octaves;
-                noise_fn.frequency = freq;
-                nfn =  NoiseFunction::BSS(noise_fn);
-            }
-            NoiseType::RMSS => {
-                let mut noise_fn: RidgedMulti<SuperSimplex> = RidgedMulti::new(seed);
-                noise_fn.octaves = octaves;
-                noise_fn.frequency = freq;
-                nfn =  NoiseFunction::RMSS(noise_fn);
-            }
-            NoiseType::HMSS => {
-                let mut noise_fn: HybridMulti<SuperSimplex> = HybridMulti::new(seed);
-                noise_fn.octaves = octaves;
-                noise_fn.frequency = freq;
-                nfn = NoiseFunction::HMSS(noise_fn);
-            }
-            
+        match key.typ {
+            NoiseType::Perlin =>           {nfn = NoiseFunction::Perlin(Perlin::new(seed))}
+            NoiseType::PerlinSurflet =>    {nfn = NoiseFunction::PerlinSurflet(PerlinSurflet::new(seed))}
+            NoiseType::Value =>            {nfn = NoiseFunction::Value(Value::new(seed))}
+            NoiseType::OpenSimplex =>      {nfn = NoiseFunction::OpenSimplex(OpenSimplex::new(seed))}
+            NoiseType::SuperSimplex =>     {nfn = NoiseFunction::SuperSimplex(SuperSimplex::new(seed))}
+            NoiseType::Worley =>           {nfn = NoiseFunction::Worley(worley(key))}
+            NoiseType::Simplex =>          {nfn = NoiseFunction::Simplex(Simplex::new(seed))}
+            NoiseType::FBMPerlin =>        {nfn = NoiseFunction::FBMPerlin(fractal(Fbm::new(seed), key))}
+            NoiseType::BMPerlin =>         {nfn = NoiseFunction::BMPerlin(fractal(BasicMulti::new(seed), key))}
+            NoiseType::BPerlin =>          {nfn = NoiseFunction::BPerlin(fractal(Billow::new(seed), key))}
+            NoiseType::RMPerlin =>         {nfn = NoiseFunction::RMPerlin(ridged(fractal(RidgedMulti::new(seed), key), key))}
+            NoiseType::HMPerlin =>         {nfn = NoiseFunction::HMPerlin(fractal(HybridMulti::new(seed), key))}
+            NoiseType::FBMPerlinSurflet => {nfn = NoiseFunction::FBMPerlinSurflet(fractal(Fbm::new(seed), key))}
+            NoiseType::BMPerlinSurflet =>  {nfn = NoiseFunction::BMPerlinSurflet(fractal(BasicMulti::new(seed), key))}
+            NoiseType::BPerlinSurflet =>   {nfn = NoiseFunction::BPerlinSurflet(fractal(Billow::new(seed), key))}
+            NoiseType::RMPerlinSurflet =>  {nfn = NoiseFunction::RMPerlinSurflet(ridged(fractal(RidgedMulti::new(seed), key), key))}
+            NoiseType::HMPerlinSurflet =>  {nfn = NoiseFunction::HMPerlinSurflet(fractal(HybridMulti::new(seed), key))}
+            NoiseType::FBMValue =>         {nfn = NoiseFunction::FBMValue(fractal(Fbm::new(seed), key))}
+            NoiseType::BMValue =>          {nfn = NoiseFunction::BMValue(fractal(BasicMulti::new(seed), key))}
+            NoiseType::BValue =>           {nfn = NoiseFunction::BValue(fractal(Billow::new(seed), key))}
+            NoiseType::RMValue =>          {nfn = NoiseFunction::RMValue(ridged(fractal(RidgedMulti::new(seed), key), key))}
+            NoiseType::HMValue =>          {nfn = NoiseFunction::HMValue(fractal(HybridMulti::new(seed), key))}
+            NoiseType::FBMSS =>            {nfn = NoiseFunction::FBMSS(fractal(Fbm::new(seed), key))}
+            NoiseType::BMSS =>             {nfn = NoiseFunction::BMSS(fractal(BasicMulti::new(seed), key))}
+            NoiseType::BSS =>              {nfn = NoiseFunction::BSS(fractal(Billow::new(seed), key))}
+            NoiseType::RMSS =>             {nfn = NoiseFunction::RMSS(ridged(fractal(RidgedMulti::new(seed), key), key))}
+            NoiseType::HMSS =>             {nfn = NoiseFunction::HMSS(fractal(HybridMulti::new(seed), key))}
         }
         return nfn;
     }
 }
 
+fn fractal<F: MultiFractal>(noise_fn: F, key: &NoiseKey) -> F {
+    let mut noise_fn = noise_fn.set_octaves(key.octaves).set_frequency(key.freq);
+    if let Some(lacunarity) = key.lacunarity {
+        noise_fn = noise_fn.set_lacunarity(lacunarity);
+    }
+    if let Some(persistence) = key.persistence {
+        noise_fn = noise_fn.set_persistence(persistence);
+    }
+    return noise_fn;
+}
+
+fn ridged<T: Default + Seedable>(noise_fn: RidgedMulti<T>, key: &NoiseKey) -> RidgedMulti<T> {
+    match key.attenuation {
+        Some(attenuation) => {return noise_fn.set_attenuation(attenuation);}
+        None => {return noise_fn;}
+    }
+}
+
+fn worley(key: &NoiseKey) -> Worley {
+    let noise_fn = Worley::new(key.seed).set_return_type(key.worley_return.into());
+    match key.worley_distance {
+        WorleyDistance::Euclidean =>        {return noise_fn.set_distance_function(distance_functions::euclidean);}
+        WorleyDistance::EuclideanSquared => {return noise_fn.set_distance_function(distance_functions::euclidean_squared);}
+        WorleyDistance::Manhattan =>        {return noise_fn.set_distance_function(distance_functions::manhattan);}
+        WorleyDistance::Chebyshev =>        {return noise_fn.set_distance_function(distance_functions::chebyshev);}
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WorleyDistance {
+    #[default]
+    Euclidean,
+    EuclideanSquared,
+    Manhattan,
+    Chebyshev
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WorleyReturnType {
+    #[default]
+    Value,
+    Distance
+}
+
+impl From<WorleyReturnType> for ReturnType {
+    fn from(return_type: WorleyReturnType) -> Self {
+        match return_type {
+            WorleyReturnType::Value => {return ReturnType::Value;}
+            WorleyReturnType::Distance => {return ReturnType::Distance;}
+        }
+    }
+}
+
 
 
 impl<'a> NoiseType {
@@ -345,4 +480,96 @@ impl<'a> NoiseType {
     ];
     NOISES_OPTIONS.iter()
   }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const POINT: Vec3 = Vec3::new(0.37, 0.0, 1.91);
+
+    fn noise(typ: NoiseType) -> Noise {
+        Noise {
+            typ,
+            seed: 7,
+            ..Noise::new()
+        }
+    }
+
+    // The changed noise has to differ from the base one and give the same value on every call
+    fn assert_changes(base: &Noise, changed: &Noise) {
+        let before = base.apply(POINT);
+        let after = changed.apply(POINT);
+        assert_ne!(before, after);
+        assert_eq!(before, base.apply(POINT));
+        assert_eq!(after, changed.apply(POINT));
+        assert_eq!(after, changed.apply_uncached(POINT));
+    }
+
+    #[test]
+    fn lacunarity_changes_output() {
+        let base = noise(NoiseType::FBMPerlin);
+        assert_changes(&base, &Noise{lacunarity: Some(3.1), ..base.clone()});
+    }
+
+    #[test]
+    fn persistence_changes_output() {
+        let base = noise(NoiseType::FBMValue);
+        assert_changes(&base, &Noise{persistence: Some(0.8), ..base.clone()});
+    }
+
+    #[test]
+    fn attenuation_changes_output() {
+        let base = noise(NoiseType::RMPerlin);
+        assert_changes(&base, &Noise{attenuation: Some(5.0), ..base.clone()});
+    }
+
+    #[test]
+    fn worley_distance_changes_output() {
+        let base = Noise{worley_return: WorleyReturnType::Distance, ..noise(NoiseType::Worley)};
+        assert_changes(&base, &Noise{worley_distance: WorleyDistance::Manhattan, ..base.clone()});
+        assert_changes(&base, &Noise{worley_distance: WorleyDistance::Chebyshev, ..base.clone()});
+    }
+
+    #[test]
+    fn worley_return_type_changes_output() {
+        let base = noise(NoiseType::Worley);
+        assert_changes(&base, &Noise{worley_return: WorleyReturnType::Distance, ..base.clone()});
+    }
+
+    #[test]
+    fn noise_json_round_trip() {
+        let noise = Noise {
+            lacunarity: Some(2.5),
+            attenuation: Some(1.5),
+            worley_distance: WorleyDistance::EuclideanSquared,
+            warp: Some(Box::new(DomainWarp::new(vec![Noise::new()], 0.5))),
+            ..noise(NoiseType::RMSS)
+        };
+        let json = serde_json::to_string(&noise).unwrap();
+        let back: Noise = serde_json::from_str(&json).unwrap();
+        assert_eq!(json, serde_json::to_string(&back).unwrap());
+        assert_eq!(noise.apply(POINT), back.apply(POINT));
+    }
+
+    #[test]
+    fn warp_without_iterations_deserializes() {
+        let warp = DomainWarp::new(vec![Noise::new()], 0.5);
+        let json = serde_json::to_string(&warp).unwrap();
+        let old_json = json.replace(",\"iterations\":1", "");
+        assert_ne!(json, old_json);
+        let back: DomainWarp = serde_json::from_str(&old_json).unwrap();
+        assert_eq!(back.iterations, 1);
+        assert_eq!(back.warp(0.3, 1.7), warp.warp(0.3, 1.7));
+    }
+
+    #[test]
+    fn noise_type_names_round_trip() {
+        assert_eq!(serde_json::to_string(&NoiseType::FBMSS).unwrap(), "\"fbm_super_simplex\"");
+        assert_eq!(serde_json::to_string(&NoiseType::RMPerlinSurflet).unwrap(), "\"ridged_multi_perlin_surflet\"");
+        for typ in NoiseType::iterator(){
+            let json = serde_json::to_string(typ).unwrap();
+            let back: NoiseType = serde_json::from_str(&json).unwrap();
+            assert_eq!(*typ, back);
+        }
+    }
+}
@@ -0,0 +1,274 @@
+use bevy::prelude::*;
+use bevy::mesh::{Indices, PrimitiveTopology, VertexAttributeValues};
+
+use crate::erosion::GridArea;
+use crate::planes::PlaneToEdit;
+
+// Flat shading keeps the shared grid at the start of the vertex buffer, where brushes, the heightfield
+// and raycasts read it, and appends three unshared corners per triangle that the index buffer draws instead
+#[derive(Component, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TerrainShading {
+    #[default]
+    Smooth,
+    Flat
+}
+
+fn grid_position(positions: &[[f32;3]], cols: usize, x: usize, z: usize) -> Vec3 {
+    return Vec3::from(positions[z*cols + x]);
+}
+
+// Smooth normal of grid vertex (x, z) on a cols x rows grid, position looks up any vertex of that grid.
+// Shared by plane meshes and by chunk seams where the grid spans several meshes.
+pub(crate) fn grid_normal(
+    position: &impl Fn(usize, usize) -> Vec3,
+    cols:     usize,
+    rows:     usize,
+    x:        usize,
+    z:        usize
+) -> Vec3 {
+    let tangent_x = position((x+1).min(cols-1), z) - position(x.saturating_sub(1), z);
+    let tangent_z = position(x, (z+1).min(rows-1)) - position(x, z.saturating_sub(1));
+    return tangent_z.cross(tangent_x).normalize_or(Vec3::Y);
+}
+
+// Writes normals for every grid vertex inside the area, positions are laid out as in plane_mesh
+pub fn grid_normals(
+    positions: &[[f32;3]],
+    normals:   &mut [[f32;3]],
+    cols:      usize,
+    rows:      usize,
+    area:      GridArea
+){
+    if cols < 2 || rows < 2 || positions.len() < cols*rows || normals.len() < cols*rows {
+        return;
+    }
+    let position = |x: usize, z: usize| grid_position(positions, cols, x, z);
+    for z in area.min_z..=area.max_z.min(rows-1) {
+        for x in area.min_x..=area.max_x.min(cols-1) {
+            normals[z*cols + x] = grid_normal(&position, cols, rows, x, z).into();
+        }
+    }
+}
+
+// Grid corners of the two triangles of quad (x, z), in the order of the Plane3d mesh indices
+fn quad_corners(cols: usize, x: usize, z: usize) -> [usize; 6] {
+    let quad = z*cols + x;
+    return [quad + cols + 1, quad + 1, quad + cols, quad, quad + cols, quad + 1];
+}
+
+fn grid_indices(cols: usize, rows: usize) -> impl Iterator<Item = u32> {
+    return (0..rows-1)
+        .flat_map(move |z| (0..cols-1).map(move |x| (x, z)))
+        .flat_map(move |(x, z)| quad_corners(cols, x, z))
+        .map(|index| index as u32);
+}
+
+fn face_vertex_count(cols: usize, rows: usize) -> usize {
+    return 6*(cols-1)*(rows-1);
+}
+
+// Shading whose layout the vertex buffer of a cols x rows plane has, None when it is not a plane_mesh
+fn mesh_layout(count: usize, cols: usize, rows: usize) -> Option<TerrainShading> {
+    if cols < 2 || rows < 2 {
+        return None;
+    }
+    if count == cols*rows {
+        return Some(TerrainShading::Smooth);
+    }
+    if count == cols*rows + face_vertex_count(cols, rows) {
+        return Some(TerrainShading::Flat);
+    }
+    return None;
+}
+
+// Vertex i of the rebuilt buffers copies every attribute of vertex sources[i]
+fn rebuild_vertices(mesh: &mut Mesh, sources: Vec<u32>, indices: Vec<u32>){
+    mesh.insert_indices(Indices::U32(sources));
+    mesh.duplicate_vertices();
+    mesh.insert_indices(Indices::U32(indices));
+}
+
+// Converts a plane mesh between the shared grid layout and the flat one, the grid part is kept as is
+pub fn set_mesh_layout(mesh: &mut Mesh, plane: &PlaneToEdit, shading: TerrainShading){
+    let Some(count) = mesh.attribute(Mesh::ATTRIBUTE_POSITION).and_then(|attr| attr.as_float3()).map(|positions| positions.len()) else {return;};
+    let (cols, rows) = plane.grid_size();
+    let Some(layout) = mesh_layout(count, cols, rows) else {return;};
+    if layout == shading {
+        return;
+    }
+    let grid_count = cols*rows;
+    match shading {
+        TerrainShading::Smooth => {
+            rebuild_vertices(mesh, (0..grid_count as u32).collect(), grid_indices(cols, rows).collect());
+        }
+        TerrainShading::Flat => {
+            let sources: Vec<u32> = (0..grid_count as u32).chain(grid_indices(cols, rows)).collect();
+            let face_count = face_vertex_count(cols, rows);
+            rebuild_vertices(mesh, sources, (grid_count as u32..(grid_count + face_count) as u32).collect());
+        }
+    }
+}
+
+// Quads with a corner inside the area, with the offset of their first corner after the grid vertices
+fn area_quads(area: GridArea, cols: usize, rows: usize) -> impl Iterator<Item = (usize, [usize; 6])> {
+    return (area.min_z..area.max_z.min(rows-1))
+        .flat_map(move |z| (area.min_x..area.max_x.min(cols-1)).map(move |x| (x, z)))
+        .map(move |(x, z)| (6*(z*(cols-1) + x), quad_corners(cols, x, z)));
+}
+
+// Copies the grid into the unshared corners of the quads around the area and gives every triangle its face normal
+fn flat_normals(mesh: &mut Mesh, cols: usize, rows: usize, area: GridArea){
+    let grid_count = cols*rows;
+    let count = grid_count + face_vertex_count(cols, rows);
+    let (mut normals, area) = match mesh.remove_attribute(Mesh::ATTRIBUTE_NORMAL) {
+        Some(VertexAttributeValues::Float32x3(normals)) if normals.len() == count => {(normals, area)}
+        _ => {(vec![[0.0, 1.0, 0.0]; count], GridArea::full(cols, rows))}
+    };
+    if let Some(VertexAttributeValues::Float32x3(positions)) = mesh.attribute_mut(Mesh::ATTRIBUTE_POSITION) {
+        let (grid, faces) = positions.split_at_mut(grid_count);
+        for (first, corners) in area_quads(area, cols, rows) {
+            for (offset, corner) in corners.iter().enumerate(){
+                faces[first + offset] = grid[*corner];
+            }
+            for triangle in [first, first + 3] {
+                let [a, b, c] = [faces[triangle], faces[triangle + 1], faces[triangle + 2]].map(Vec3::from);
+                let normal = (b - a).cross(c - a).normalize_or(Vec3::Y);
+                normals[grid_count + triangle..grid_count + triangle + 3].fill(normal.into());
+            }
+        }
+    }
+    if let Some(VertexAttributeValues::Float32x4(colors)) = mesh.attribute_mut(Mesh::ATTRIBUTE_COLOR) && colors.len() == count {
+        let (grid, faces) = colors.split_at_mut(grid_count);
+        for (first, corners) in area_quads(area, cols, rows) {
+            for (offset, corner) in corners.iter().enumerate(){
+                faces[first + offset] = grid[*corner];
+            }
+        }
+    }
+    mesh.insert_attribute(Mesh::ATTRIBUTE_NORMAL, normals);
+}
+
+// Recomputes the normals around the changed indices, or the whole mesh when indices is None.
+// A mesh in the layout of the other shading is converted first, which rewrites every normal.
+pub fn update_normals(
+    mesh:    &mut Mesh,
+    plane:   &PlaneToEdit,
+    shading: TerrainShading,
+    indices: Option<&[usize]>
+){
+    let Some(count) = mesh.attribute(Mesh::ATTRIBUTE_POSITION).and_then(|attr| attr.as_float3()).map(|positions| positions.len()) else {return;};
+    let (cols, rows) = plane.grid_size();
+    let Some(layout) = mesh_layout(count, cols, rows) else {
+        // Not a plane_mesh layout, let bevy handle it if it can
+        if mesh.primitive_topology() == PrimitiveTopology::TriangleList && mesh.indices().is_some() {
+            mesh.compute_smooth_normals();
+        }
+        return;
+    };
+    let indices = if layout == shading {indices} else {None};
+    set_mesh_layout(mesh, plane, shading);
+
+    let area = match indices {
+        Some(indices) => {
+            let Some(area) = GridArea::from_indices(indices.iter().copied().filter(|i| *i < cols*rows), cols) else {return;};
+            // Neighbours of the changed vertices use them in their own normals
+            GridArea {
+                min_x: area.min_x.saturating_sub(1),
+                min_z: area.min_z.saturating_sub(1),
+                max_x: (area.max_x + 1).min(cols-1),
+                max_z: (area.max_z + 1).min(rows-1)
+            }
+        }
+        None => {GridArea::full(cols, rows)}
+    };
+    if shading == TerrainShading::Flat {
+        flat_normals(mesh, cols, rows, area);
+        return;
+    }
+
+    // The normal buffer is moved out while positions are read, nothing is copied
+    let count = cols*rows;
+    let (mut normals, area) = match mesh.remove_attribute(Mesh::ATTRIBUTE_NORMAL) {
+        Some(VertexAttributeValues::Float32x3(normals)) if normals.len() == count => {(normals, area)}
+        _ => {(vec![[0.0, 1.0, 0.0]; count], GridArea::full(cols, rows))}
+    };
+    if let Some(positions) = mesh.attribute(Mesh::ATTRIBUTE_POSITION).and_then(|attr| attr.as_float3()) {
+        grid_normals(positions, &mut normals, cols, rows, area);
+    }
+    mesh.insert_attribute(Mesh::ATTRIBUTE_NORMAL, normals);
+}
+
+pub(crate) fn shading_changed(
+    planes:     Query<(&Mesh3d, &PlaneToEdit, &TerrainShading), Changed<TerrainShading>>,
+    mut meshes: ResMut<Assets<Mesh>>
+){
+    for (mesh3d, plane, shading) in planes.iter(){
+        let Some(mesh) = meshes.get_mut(&mesh3d.0) else {continue;};
+        update_normals(mesh, plane, *shading, None);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn constant_slope_normals() {
+        let (cols, rows) = (6, 5);
+        let (slope_x, slope_z) = (0.5, -0.25);
+        let positions: Vec<[f32;3]> = (0..cols*rows)
+            .map(|i| {
+                let (x, z) = ((i % cols) as f32 * 0.4, (i / cols) as f32 * 0.4);
+                [x, slope_x*x + slope_z*z, z]
+            })
+            .collect();
+        let mut normals = vec![[0.0, 1.0, 0.0]; cols*rows];
+        grid_normals(&positions, &mut normals, cols, rows, GridArea::full(cols, rows));
+
+        // Every vertex, borders included, is perpendicular to the slope
+        let expected = Vec3::new(-slope_x, 1.0, -slope_z).normalize();
+        for normal in normals.iter(){
+            assert!(Vec3::from(*normal).distance(expected) < 1e-5, "{normal:?} != {expected:?}");
+        }
+    }
+
+    #[test]
+    fn flat_shading_uses_face_normals() {
+        // Slope 0.5 on the left half of the grid and -1.5 on the right half, one unit per cell
+        let plane = PlaneToEdit::new(4.0, 2.0, 3);
+        let (cols, rows) = plane.grid_size();
+        let (slope_left, slope_right) = (0.5, -1.5);
+        let mut mesh = Plane3d::default().mesh().size(4.0, 2.0).subdivisions(3).build();
+        let heights = |x: f32| if x <= 0.0 {slope_left*x} else {slope_right*x};
+        let positions: Vec<[f32;3]> = mesh.attribute(Mesh::ATTRIBUTE_POSITION).unwrap().as_float3().unwrap()
+            .iter()
+            .map(|pos| [pos[0], heights(pos[0]), pos[2]])
+            .collect();
+        mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, positions.clone());
+
+        update_normals(&mut mesh, &plane, TerrainShading::Flat, None);
+        let flat_positions = mesh.attribute(Mesh::ATTRIBUTE_POSITION).unwrap().as_float3().unwrap();
+        assert_eq!(&flat_positions[..cols*rows], &positions[..]);
+        let Some(Indices::U32(indices)) = mesh.indices() else {panic!("plane mesh has u32 indices");};
+        assert!(indices.iter().all(|index| *index as usize >= cols*rows));
+
+        // Every drawn corner has the normal of its quad, quads meet at the crease without blending
+        let normals = mesh.attribute(Mesh::ATTRIBUTE_NORMAL).unwrap().as_float3().unwrap();
+        let left = Vec3::new(-slope_left, 1.0, 0.0).normalize();
+        let right = Vec3::new(-slope_right, 1.0, 0.0).normalize();
+        for z in 0..rows-1 {
+            for x in 0..cols-1 {
+                let expected = if x < (cols-1)/2 {left} else {right};
+                let first = cols*rows + 6*(z*(cols-1) + x);
+                for normal in normals[first..first + 6].iter(){
+                    assert!(Vec3::from(*normal).distance(expected) < 1e-5, "quad ({x}, {z}): {normal:?} != {expected:?}");
+                }
+            }
+        }
+
+        update_normals(&mut mesh, &plane, TerrainShading::Smooth, None);
+        assert_eq!(mesh.attribute(Mesh::ATTRIBUTE_POSITION).unwrap().len(), cols*rows);
+        let Some(Indices::U32(indices)) = mesh.indices() else {panic!("plane mesh has u32 indices");};
+        assert_eq!(indices.to_vec(), grid_indices(cols, rows).collect::<Vec<u32>>());
+    }
+}
@@ -1,4 +1,9 @@
 use bevy::prelude::*;
+use bevy::camera::primitives::{Aabb, MeshAabb};
+use bevy::ecs::system::SystemState;
+use std::collections::HashSet;
+
+use crate::heightfield::TerrainHeightfield;
 
 pub fn plane_mesh(
     width: f32,
@@ -8,7 +13,7 @@ pub fn plane_mesh(
 ) -> impl Bundle {
     (
         Mesh3d(meshes.add(Plane3d::default().mesh().size(width, height).subdivisions(subdivisions))),
-        PlaneToEdit{width, height, subdivisions}
+        PlaneToEdit::new(width, height, subdivisions)
     )
 }
 
@@ -18,28 +23,102 @@ pub fn plane_mesh(
 pub struct PlaneToEdit{
     pub width: f32,
     pub height: f32,
-    pub subdivisions: u32
+    pub subdivisions: u32,
+    pub height_range: [f32;2] // local [min, max] vertex height, kept in sync with the mesh
 }
 
 impl PlaneToEdit {
-    pub fn dummy() -> Self {
+    pub fn new(width: f32, height: f32, subdivisions: u32) -> Self {
         PlaneToEdit {
-            width: 0.0,
-            height: 0.0,
-            subdivisions: 0
+            width,
+            height,
+            subdivisions,
+            height_range: [0.0, 0.0]
+        }
+    }
+
+    pub fn dummy() -> Self {
+        PlaneToEdit::new(0.0, 0.0, 0)
+    }
+
+    // Recovers the plane layout from a mesh built by plane_mesh
+    pub fn from_mesh(mesh: &Mesh) -> Option<PlaneToEdit> {
+        let positions = mesh.attribute(Mesh::ATTRIBUTE_POSITION)?.as_float3()?;
+        let side = (positions.len() as f64).sqrt().round() as usize;
+        if side < 2 || side*side != positions.len() {
+            return None;
+        }
+        let mut min = Vec3::splat(f32::MAX);
+        let mut max = Vec3::splat(f32::MIN);
+        for pos in positions.iter(){
+            min = min.min(Vec3::from(*pos));
+            max = max.max(Vec3::from(*pos));
+        }
+        return Some(PlaneToEdit {
+            width: max.x - min.x,
+            height: max.z - min.z,
+            subdivisions: (side - 2) as u32,
+            height_range: [min.y, max.y]
+        });
+    }
+
+    // Culling box of the plane mesh, matches compute_aabb once height_range is fitted
+    pub fn aabb(&self) -> Aabb {
+        return Aabb::from_min_max(
+            Vec3::new(-self.width*0.5, self.height_range[0], -self.height*0.5),
+            Vec3::new(self.width*0.5, self.height_range[1], self.height*0.5)
+        );
+    }
+
+    // Widens height_range to the heights, returns whether it changed
+    pub fn grow_height_range(&mut self, heights: impl Iterator<Item = f32>) -> bool {
+        let [mut min, mut max] = self.height_range;
+        for height in heights {
+            min = min.min(height);
+            max = max.max(height);
+        }
+        if [min, max] == self.height_range {
+            return false;
         }
+        self.height_range = [min, max];
+        return true;
+    }
+
+    pub fn grid_size(&self) -> (usize, usize) {
+        let count = self.subdivisions as usize + 2;
+        return (count, count);
+    }
+
+    // Distance between neighbouring vertices along x and z
+    pub fn cell_size(&self) -> Vec2 {
+        let (cols, rows) = self.grid_size();
+        return Vec2::new(
+            self.width/(cols - 1) as f32,
+            self.height/(rows - 1) as f32
+        );
+    }
+
+    pub fn vertex_count(&self) -> usize {
+        let (cols, rows) = self.grid_size();
+        return cols*rows;
     }
 
+    // Entry distance into the plane's bounding box, the ray is in world space
     pub fn ray_intersection(
         &self, 
-        loc: Vec3, 
-        scale: Vec3, 
+        plane_transform: &GlobalTransform, 
         origin: Vec3A, 
         direction: Vec3A
     ) -> Option<f32> {
 
-        let min_corner = Vec3A::new(loc.x - self.width*0.5*scale.x, loc.y, loc.z - self.height*0.5*scale.y);
-        let max_corner = Vec3A::new(loc.x + self.width*0.5*scale.x, loc.y, loc.z + self.height*0.5*scale.y);
+        let world_from_local = plane_transform.affine();
+        let local_from_world = world_from_local.inverse();
+        let origin = local_from_world.transform_point3a(origin);
+        let direction = local_from_world.transform_vector3a(direction);
+
+        // Box around the sculpted surface, the hit is where the ray enters it
+        let min_corner = Vec3A::new(-self.width*0.5, self.height_range[0], -self.height*0.5);
+        let max_corner = Vec3A::new(self.width*0.5, self.height_range[1], self.height*0.5);
 
         let inv_dir = direction.recip();
         
@@ -59,5 +138,332 @@ impl PlaneToEdit {
             return None;
         }
     }
+
+    // Walks the grid cells under the ray and returns the first triangle hit.
+    // positions are the plane mesh positions in local space, the ray is in world space.
+    pub fn raycast(
+        &self,
+        positions: &[[f32;3]],
+        plane_transform: &GlobalTransform,
+        origin: Vec3A,
+        direction: Vec3A
+    ) -> Option<TerrainRayHit> {
+        let (cols, rows) = self.grid_size();
+        if positions.len() < cols*rows || self.width <= 0.0 || self.height <= 0.0 {
+            return None;
+        }
+        let world_from_local = plane_transform.affine();
+        let local_from_world = world_from_local.inverse();
+        // Affine maps keep the ray parameter, so t is shared between both spaces
+        let local_origin = Vec3::from(local_from_world.transform_point3a(origin));
+        let local_direction = Vec3::from(local_from_world.transform_vector3a(direction));
+
+        let half = Vec2::new(self.width*0.5, self.height*0.5);
+        let o = local_origin.xz();
+        let d = local_direction.xz();
+        let mut t_enter: f32 = 0.0;
+        let mut t_exit: f32 = f32::MAX;
+        for axis in 0..2 {
+            if d[axis].abs() < f32::EPSILON {
+                if o[axis] < -half[axis] || o[axis] > half[axis] {
+                    return None;
+                }
+                continue;
+            }
+            let ta = (-half[axis] - o[axis])/d[axis];
+            let tb = (half[axis] - o[axis])/d[axis];
+            t_enter = t_enter.max(ta.min(tb));
+            t_exit = t_exit.min(ta.max(tb));
+        }
+        if t_enter > t_exit {
+            return None;
+        }
+
+        let cell = self.cell_size();
+        let start = o + d*t_enter + half;
+        let mut cx: i64 = ((start.x/cell.x).floor() as i64).clamp(0, cols as i64 - 2);
+        let mut cz: i64 = ((start.y/cell.y).floor() as i64).clamp(0, rows as i64 - 2);
+        let step_x: i64 = if d.x > 0.0 {1} else {-1};
+        let step_z: i64 = if d.y > 0.0 {1} else {-1};
+        let delta_x: f32 = if d.x.abs() < f32::EPSILON {f32::MAX} else {cell.x/d.x.abs()};
+        let delta_z: f32 = if d.y.abs() < f32::EPSILON {f32::MAX} else {cell.y/d.y.abs()};
+        let boundary_x: f32 = (cx + if step_x > 0 {1} else {0}) as f32 * cell.x - half.x;
+        let boundary_z: f32 = (cz + if step_z > 0 {1} else {0}) as f32 * cell.y - half.y;
+        let mut next_x: f32 = if d.x.abs() < f32::EPSILON {f32::MAX} else {(boundary_x - o.x)/d.x};
+        let mut next_z: f32 = if d.y.abs() < f32::EPSILON {f32::MAX} else {(boundary_z - o.y)/d.y};
+
+        while cx >= 0 && cz >= 0 && cx < cols as i64 - 1 && cz < rows as i64 - 1 {
+            let quad = cz as usize*cols + cx as usize;
+            let mut best: Option<(f32, [usize;3])> = None;
+            // Same split as the Plane3d mesh indices
+            for triangle in [[quad + cols + 1, quad + 1, quad + cols], [quad, quad + cols, quad + 1]]{
+                let Some(t) = ray_triangle(local_origin, local_direction, positions, triangle) else {continue;};
+                if best.is_none_or(|(best_t, _)| t < best_t) {
+                    best = Some((t, triangle));
+                }
+            }
+            if let Some((t, triangle)) = best {
+                let a = Vec3::from(positions[triangle[0]]);
+                let b = Vec3::from(positions[triangle[1]]);
+                let c = Vec3::from(positions[triangle[2]]);
+                let local_normal = (b - a).cross(c - a);
+                let normal = (world_from_local.matrix3.inverse().transpose() * Vec3A::from(local_normal)).normalize_or_zero();
+                return Some(TerrainRayHit {
+                    distance: t,
+                    point: Vec3::from(origin + direction*t),
+                    local_point: local_origin + local_direction*t,
+                    normal: Vec3::from(normal),
+                    triangle
+                });
+            }
+            if next_x.min(next_z) >= t_exit {
+                break;
+            }
+            if next_x < next_z {
+                cx += step_x;
+                next_x += delta_x;
+            } else {
+                cz += step_z;
+                next_z += delta_z;
+            }
+        }
+        return None;
+    }
+
+    pub fn raycast_mesh(
+        &self,
+        mesh: &Mesh,
+        plane_transform: &GlobalTransform,
+        origin: Vec3A,
+        direction: Vec3A
+    ) -> Option<TerrainRayHit> {
+        let positions = mesh.attribute(Mesh::ATTRIBUTE_POSITION)?.as_float3()?;
+        return self.raycast(positions, plane_transform, origin, direction);
+    }
+}
+
+// Left, right, lower and upper neighbours of a vertex on a cols x rows grid laid out as in plane_mesh.
+// Takes the grid size and not a PlaneToEdit so chunked terrains can walk their global grid too.
+pub fn grid_neighbours(cols: usize, rows: usize, index: usize) -> impl Iterator<Item = usize> {
+    let (x, z) = (index % cols.max(1), index / cols.max(1));
+    let inside = index < cols*rows;
+    return [
+        (x > 0, index.wrapping_sub(1)),
+        (x + 1 < cols, index + 1),
+        (z > 0, index.wrapping_sub(cols)),
+        (z + 1 < rows, index + cols)
+    ]
+        .into_iter()
+        .filter(move |(valid, _)| inside && *valid)
+        .map(|(_, neighbour)| neighbour);
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct TerrainRayHit {
+    pub distance: f32,       // ray parameter, world distance when the direction is normalized
+    pub point: Vec3,         // world space
+    pub local_point: Vec3,   // plane space
+    pub normal: Vec3,        // world space
+    pub triangle: [usize;3]  // vertex indices
+}
+
+// Double sided Moller-Trumbore
+fn ray_triangle(origin: Vec3, direction: Vec3, positions: &[[f32;3]], triangle: [usize;3]) -> Option<f32> {
+    let a = Vec3::from(positions[triangle[0]]);
+    let b = Vec3::from(positions[triangle[1]]);
+    let c = Vec3::from(positions[triangle[2]]);
+    let edge1 = b - a;
+    let edge2 = c - a;
+    let p = direction.cross(edge2);
+    let det = edge1.dot(p);
+    if det.abs() < 1e-8 {
+        return None;
+    }
+    let inv_det = 1.0/det;
+    let s = origin - a;
+    let u = s.dot(p)*inv_det;
+    if !(0.0..=1.0).contains(&u) {
+        return None;
+    }
+    let q = s.cross(edge1);
+    let v = direction.dot(q)*inv_det;
+    if v < 0.0 || u + v > 1.0 {
+        return None;
+    }
+    let t = edge2.dot(q)*inv_det;
+    if t < 0.0 {
+        return None;
+    }
+    return Some(t);
+}
+
+// Horizontal distance in world units between a brush location and a vertex, both in plane space.
+// Brushes work along the plane's up axis so rotated and scaled planes are sculpted like flat ones.
+pub fn plane_distance(plane_transform: &GlobalTransform, local_loc: Vec3, local_vertex: Vec3) -> f32 {
+    let offset = Vec3::new(local_vertex.x - local_loc.x, 0.0, local_vertex.z - local_loc.z);
+    return plane_transform.affine().transform_vector3(offset).length();
+}
+
+// World height of a plane space position
+pub fn world_height(plane_transform: &GlobalTransform, local_pos: Vec3) -> f32 {
+    return plane_transform.transform_point(local_pos).y;
+}
+
+// Plane space height that puts the vertex at (local_pos.x, local_pos.z) at the world height,
+// None when the plane's up axis has no vertical component.
+pub fn local_height(plane_transform: &GlobalTransform, local_pos: Vec3, world_height: f32) -> Option<f32> {
+    let world_from_local = plane_transform.affine();
+    let up_y: f32 = world_from_local.matrix3.y_axis.y;
+    if up_y.abs() < f32::EPSILON {
+        return None;
+    }
+    let base_y: f32 = world_from_local.transform_point3(Vec3::new(local_pos.x, 0.0, local_pos.z)).y;
+    return Some((world_height - base_y)/up_y);
+}
+
+// Fits the culling Aabb and height range to newly added plane meshes.
+// Edits only grow them in vertex_changed, fit_plane_bounds tightens them again when a stroke ends.
+pub(crate) fn update_plane_bounds(
+    mut commands:    Commands,
+    mut mesh_events: MessageReader<AssetEvent<Mesh>>,
+    mut planes:      Query<(Entity, &Mesh3d, &mut PlaneToEdit)>,
+    meshes:          Res<Assets<Mesh>>
+){
+    let added: HashSet<AssetId<Mesh>> = mesh_events
+        .read()
+        .filter_map(|mesh_event| match mesh_event {
+            AssetEvent::Added{id} | AssetEvent::LoadedWithDependencies{id} => {Some(*id)}
+            _ => {None}
+        })
+        .collect();
+    if added.is_empty() {
+        return;
+    }
+    for (plane_entity, mesh3d, mut plane) in planes.iter_mut(){
+        if !added.contains(&mesh3d.0.id()) {
+            continue;
+        }
+        let Some(mesh) = meshes.get(&mesh3d.0) else {continue;};
+        let Some(aabb) = mesh.compute_aabb() else {continue;};
+        plane.height_range = [aabb.min().y, aabb.max().y];
+        commands.entity(plane_entity).insert(aabb);
+    }
+}
+
+type FitBoundsState = SystemState<(
+    Commands<'static, 'static>,
+    Query<'static, 'static, (Entity, &'static mut PlaneToEdit, &'static TerrainHeightfield)>
+)>;
+
+// Refits the height range of every plane to its heightfield, once per stroke instead of once per edit
+pub(crate) fn fit_plane_bounds(world: &mut World) {
+    let mut system_state: FitBoundsState = SystemState::new(world);
+    let (mut commands, mut planes) = system_state.get_mut(world);
+    for (plane_entity, mut plane, heightfield) in planes.iter_mut(){
+        if heightfield.is_empty() {
+            continue;
+        }
+        let min = heightfield.heights.iter().copied().fold(f32::MAX, f32::min);
+        let max = heightfield.heights.iter().copied().fold(f32::MIN, f32::max);
+        if plane.height_range != [min, max] {
+            plane.height_range = [min, max];
+            commands.entity(plane_entity).insert(plane.aabb());
+        }
+    }
+    system_state.apply(world);
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 4x4 plane with 5x5 vertices, sloping up along local x
+    fn sloped_plane() -> (PlaneToEdit, Vec<[f32;3]>) {
+        let plane = PlaneToEdit::new(4.0, 4.0, 3);
+        let positions: Vec<[f32;3]> = (0..plane.vertex_count())
+            .map(|i| {
+                let (x, z) = ((i % 5) as f32 - 2.0, (i / 5) as f32 - 2.0);
+                [x, 0.5*x, z]
+            })
+            .collect();
+        return (plane, positions);
+    }
+
+    fn assert_hit_on_surface(plane: &PlaneToEdit, positions: &[[f32;3]], plane_transform: &GlobalTransform, origin: Vec3, direction: Vec3) -> TerrainRayHit {
+        let hit = plane.raycast(positions, plane_transform, origin.into(), direction.into()).unwrap();
+        // On the ray, on the sloped surface, and the same point in both spaces
+        assert!(hit.point.distance(origin + direction*hit.distance) < 1e-4);
+        assert!((hit.local_point.y - 0.5*hit.local_point.x).abs() < 1e-4, "{hit:?}");
+        assert!(plane_transform.transform_point(hit.local_point).distance(hit.point) < 1e-4);
+        return hit;
+    }
+
+    #[test]
+    fn raycast_rotated_plane() {
+        let (plane, positions) = sloped_plane();
+        let rotation = Quat::from_rotation_y(std::f32::consts::FRAC_PI_2) * Quat::from_rotation_x(0.3);
+        let plane_transform = GlobalTransform::from(Transform::from_xyz(3.0, 1.0, -2.0).with_rotation(rotation));
+
+        let hit = assert_hit_on_surface(&plane, &positions, &plane_transform, Vec3::new(3.5, 10.0, -2.2), Vec3::NEG_Y);
+        assert!((hit.point.x - 3.5).abs() < 1e-4 && (hit.point.z + 2.2).abs() < 1e-4);
+        assert!(hit.normal.dot(plane_transform.affine().transform_vector3(Vec3::new(-0.5, 1.0, 0.0)).normalize()) > 0.9999);
+
+        assert_hit_on_surface(&plane, &positions, &plane_transform, Vec3::new(-2.0, 6.0, 1.0), Vec3::new(0.6, -1.0, -0.4).normalize());
+    }
+
+    #[test]
+    fn raycast_scaled_plane() {
+        let (plane, positions) = sloped_plane();
+        let plane_transform = GlobalTransform::from(Transform::from_xyz(1.0, 0.0, 0.0).with_scale(Vec3::new(2.0, 3.0, 0.5)));
+
+        let hit = assert_hit_on_surface(&plane, &positions, &plane_transform, Vec3::new(2.0, 10.0, 0.3), Vec3::NEG_Y);
+        assert!(hit.local_point.distance(Vec3::new(0.5, 0.25, 0.6)) < 1e-4);
+        assert!(hit.point.distance(Vec3::new(2.0, 0.75, 0.3)) < 1e-4);
+        assert!((hit.distance - 9.25).abs() < 1e-4);
+
+        // An oblique ray reaches the same surface point
+        let direction = Vec3::new(1.0, -1.0, 0.5).normalize();
+        let hit = assert_hit_on_surface(&plane, &positions, &plane_transform, Vec3::new(2.0, 0.75, 0.3) - direction*4.0, direction);
+        assert!(hit.point.distance(Vec3::new(2.0, 0.75, 0.3)) < 1e-4);
+        // Outside the scaled extent along z
+        assert!(plane.raycast(&positions, &plane_transform, Vec3A::new(1.0, 10.0, 1.2), Vec3A::NEG_Y).is_none());
+    }
+
+    #[test]
+    fn distance_on_rotated_and_scaled_planes() {
+        let local_loc = Vec3::new(0.5, 2.0, -1.0);
+        let local_vertex = Vec3::new(1.5, -3.0, 1.0);
+
+        let rotated = GlobalTransform::from(Transform::from_xyz(5.0, 1.0, 0.0).with_rotation(Quat::from_rotation_y(0.7)));
+        assert!((plane_distance(&rotated, local_loc, local_vertex) - 5.0_f32.sqrt()).abs() < 1e-5);
+
+        // Heights never count, x and z follow their own scale
+        let scaled = GlobalTransform::from(Transform::from_scale(Vec3::new(2.0, 4.0, 0.5)));
+        assert!((plane_distance(&scaled, local_loc, local_vertex) - 5.0_f32.sqrt()).abs() < 1e-5);
+        assert!((plane_distance(&scaled, local_loc, Vec3::new(1.5, 0.0, -1.0)) - 2.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn world_and_local_heights() {
+        let plane_transform = GlobalTransform::from(
+            Transform::from_xyz(0.0, 2.0, 0.0).with_scale(Vec3::new(1.0, 0.5, 1.0)).with_rotation(Quat::from_rotation_z(0.2))
+        );
+        let local_pos = Vec3::new(1.0, 0.0, -1.5);
+        let local_y = local_height(&plane_transform, local_pos, 3.0).unwrap();
+        assert!((world_height(&plane_transform, local_pos.with_y(local_y)) - 3.0).abs() < 1e-5);
+
+        let vertical = GlobalTransform::from(Transform::from_rotation(Quat::from_rotation_x(std::f32::consts::FRAC_PI_2)));
+        assert!(local_height(&vertical, local_pos, 3.0).is_none());
+    }
+
+    #[test]
+    fn neighbours_stay_on_the_grid() {
+        let neighbours = |index: usize| grid_neighbours(4, 3, index).collect::<Vec<usize>>();
+        assert_eq!(neighbours(5), vec![4, 6, 1, 9]);
+        assert_eq!(neighbours(0), vec![1, 4]);
+        assert_eq!(neighbours(3), vec![2, 7]);
+        assert_eq!(neighbours(8), vec![9, 4]);
+        assert_eq!(neighbours(11), vec![10, 7]);
+        assert!(neighbours(12).is_empty());
+    }
+}
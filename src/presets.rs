@@ -0,0 +1,152 @@
+use bevy::prelude::*;
+use bevy::asset::{AssetLoader, LoadContext};
+use bevy::asset::io::Reader;
+use bevy_pg_editor_tools::prelude::BrushSettings;
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+
+use crate::terrain_brushes::{TerrainHeightBrush, TerrainColorBrush};
+
+pub struct TerrainBrushPresetPlugin {
+    pub path: String
+}
+
+impl TerrainBrushPresetPlugin {
+    pub fn new(path: &str) -> Self {
+        TerrainBrushPresetPlugin {
+            path: path.to_string()
+        }
+    }
+}
+
+impl Plugin for TerrainBrushPresetPlugin {
+    fn build(&self, app: &mut App) {
+        app
+        .init_asset::<BrushPresets>()
+        .register_asset_loader(BrushPresetsLoader)
+        .insert_resource(BrushPresetPluginSettings{path: self.path.clone()})
+        .add_systems(Startup, load_presets)
+        .add_systems(Update, reload_active_preset)
+        .add_observer(activate_brush_preset)
+        ;
+    }
+}
+
+#[derive(Asset, TypePath, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct BrushPresets {
+    #[serde(default)]
+    pub height: HashMap<String, TerrainHeightBrush>,
+    #[serde(default)]
+    pub color: HashMap<String, TerrainColorBrush>
+}
+
+impl BrushPresets {
+    pub fn names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.height.keys().chain(self.color.keys()).cloned().collect();
+        names.sort();
+        names.dedup();
+        return names;
+    }
+
+    // Height presets win when a height and a color preset share a name
+    pub fn activate(&self, name: &str, brush_settings: &mut BrushSettings) -> bool {
+        if let Some(brush) = self.height.get(name) {
+            brush_settings.typ = Box::new(brush.clone());
+            return true;
+        }
+        if let Some(brush) = self.color.get(name) {
+            brush_settings.typ = Box::new(brush.clone());
+            return true;
+        }
+        return false;
+    }
+}
+
+#[derive(Default)]
+pub struct BrushPresetsLoader;
+
+impl AssetLoader for BrushPresetsLoader {
+    type Asset = BrushPresets;
+    type Settings = ();
+    type Error = std::io::Error;
+
+    async fn load(
+        &self,
+        reader:        &mut dyn Reader,
+        _settings:     &(),
+        _load_context: &mut LoadContext<'_>
+    ) -> Result<Self::Asset, Self::Error> {
+        let mut bytes: Vec<u8> = Vec::new();
+        reader.read_to_end(&mut bytes).await?;
+        let presets: BrushPresets = serde_json::from_slice(&bytes)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        return Ok(presets);
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["brushes.json"]
+    }
+}
+
+#[derive(Resource)]
+struct BrushPresetPluginSettings {
+    path: String
+}
+
+#[derive(Resource)]
+pub struct BrushPresetLibrary {
+    pub handle: Handle<BrushPresets>,
+    pub active: Option<String>
+}
+
+#[derive(Event)]
+pub struct ActivateBrushPreset {
+    pub name: String
+}
+
+fn load_presets(
+    mut commands: Commands,
+    assets:       Res<AssetServer>,
+    settings:     Res<BrushPresetPluginSettings>
+){
+    commands.insert_resource(BrushPresetLibrary{
+        handle: assets.load(settings.path.clone()),
+        active: None
+    });
+}
+
+fn activate_brush_preset(
+    trigger:            On<ActivateBrushPreset>,
+    library:            Option<ResMut<BrushPresetLibrary>>,
+    presets:            Res<Assets<BrushPresets>>,
+    mut brush_settings: ResMut<BrushSettings>
+){
+    let Some(mut library) = library else {return;};
+    library.active = Some(trigger.name.clone());
+    let Some(presets) = presets.get(&library.handle) else {return;};
+    if !presets.activate(&trigger.name, &mut brush_settings) {
+        warn!("brush preset {} not found", trigger.name);
+    }
+}
+
+// Applies the active preset again once the file is loaded or changed on disk
+fn reload_active_preset(
+    mut asset_events:   MessageReader<AssetEvent<BrushPresets>>,
+    library:            Option<Res<BrushPresetLibrary>>,
+    presets:            Res<Assets<BrushPresets>>,
+    mut brush_settings: ResMut<BrushSettings>
+){
+    let Some(library) = library else {return;};
+    let mut changed: bool = false;
+    for asset_event in asset_events.read(){
+        if asset_event.is_loaded_with_dependencies(&library.handle) || asset_event.is_modified(&library.handle) {
+            changed = true;
+        }
+    }
+    if !changed {
+        return;
+    }
+    let Some(name) = &library.active else {return;};
+    let Some(presets) = presets.get(&library.handle) else {return;};
+    presets.activate(name, &mut brush_settings);
+}
@@ -0,0 +1,433 @@
+use bevy::prelude::*;
+use bevy::mesh::SerializedMesh;
+use serde::{Serialize, Deserialize};
+use std::borrow::Cow;
+use std::path::{Component, Path, PathBuf};
+
+use crate::planes::PlaneToEdit;
+use crate::normals::{TerrainShading, set_mesh_layout};
+use crate::vertex::{SpawnVertices, load_mesh_from_file};
+use crate::chunks::{ChunkLayout, ChunkedTerrain, TerrainChunk, spawn_chunks};
+
+#[derive(Resource, Clone, Debug)]
+pub struct TerrainSaveSettings {
+    pub directory: PathBuf
+}
+
+impl Default for TerrainSaveSettings {
+    fn default() -> Self {
+        TerrainSaveSettings {
+            directory: PathBuf::from("assets/meshes")
+        }
+    }
+}
+
+impl TerrainSaveSettings {
+    pub fn new(directory: impl Into<PathBuf>) -> Self {
+        TerrainSaveSettings {
+            directory: directory.into()
+        }
+    }
+
+    pub fn path_for(&self, plane_entity: Entity, name: Option<&TerrainName>) -> std::io::Result<PathBuf> {
+        return Ok(self.directory.join(terrain_file_name(plane_entity, name)?));
+    }
+
+    pub fn manifest_path_for(&self, terrain_entity: Entity, name: Option<&TerrainName>) -> std::io::Result<PathBuf> {
+        return Ok(self.directory.join(chunk_manifest_file_name(terrain_entity, name)?));
+    }
+}
+
+#[derive(Component, Clone, Debug, PartialEq)]
+pub struct TerrainName(pub String);
+
+impl TerrainName {
+    // Names become file names inside the save directory, so separators, ".." and absolute paths are refused
+    pub fn check(&self) -> std::io::Result<()> {
+        let mut components = Path::new(&self.0).components();
+        let plain: bool = matches!((components.next(), components.next()), (Some(Component::Normal(_)), None));
+        if !plain || self.0.contains(['/', '\\']) {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("terrain name {:?} is not a plain file name", self.0)));
+        }
+        return Ok(());
+    }
+}
+
+pub fn terrain_file_name(plane_entity: Entity, name: Option<&TerrainName>) -> std::io::Result<String> {
+    match name {
+        Some(name) => {
+            name.check()?;
+            return Ok(format!("{}.json", name.0));
+        }
+        None => {return Ok(format!("terrain_{}.json", plane_entity));}
+    }
+}
+
+pub fn chunk_manifest_file_name(terrain_entity: Entity, name: Option<&TerrainName>) -> std::io::Result<String> {
+    match name {
+        Some(name) => {
+            name.check()?;
+            return Ok(format!("{}.chunks.json", name.0));
+        }
+        None => {return Ok(format!("terrain_{}.chunks.json", terrain_entity));}
+    }
+}
+
+pub fn save_mesh_to_file(mesh: &Mesh, path: &Path) -> std::io::Result<()> {
+    if let Some(directory) = path.parent() {
+        std::fs::create_dir_all(directory)?;
+    }
+    let serialized_mesh = SerializedMesh::from_mesh(mesh.clone());
+    let json = serde_json::to_string_pretty(&serialized_mesh)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+    std::fs::write(path, json)?;
+    return Ok(());
+}
+
+// Saves the given plane, or every PlaneToEdit when plane_entity is None.
+// Chunks are saved with their terrain by SaveChunkedTerrain.
+#[derive(Event)]
+pub struct SaveTerrain {
+    pub plane_entity: Option<Entity>
+}
+impl SaveTerrain {
+    pub fn all() -> Self {
+        SaveTerrain { plane_entity: None }
+    }
+    pub fn plane(plane_entity: Entity) -> Self {
+        SaveTerrain { plane_entity: Some(plane_entity) }
+    }
+}
+
+#[derive(Event, Debug)]
+pub struct TerrainSaved {
+    pub plane_entity: Entity,
+    pub path: PathBuf,
+    pub result: Result<(), String>
+}
+
+// Planes are saved in the shared grid layout, flat shading is a component and not part of the file
+fn saved_mesh<'a>(mesh: &'a Mesh, plane: &PlaneToEdit) -> Cow<'a, Mesh> {
+    if mesh.count_vertices() == plane.vertex_count() {
+        return Cow::Borrowed(mesh);
+    }
+    let mut mesh = mesh.clone();
+    set_mesh_layout(&mut mesh, plane, TerrainShading::Smooth);
+    return Cow::Owned(mesh);
+}
+
+pub(crate) fn save_terrain(
+    trigger:      On<SaveTerrain>,
+    mut commands: Commands,
+    settings:     Res<TerrainSaveSettings>,
+    meshes:       Res<Assets<Mesh>>,
+    query:        Query<(Entity, &Mesh3d, &PlaneToEdit, Option<&TerrainName>), Without<TerrainChunk>>
+){
+    for (plane_entity, mesh3d, plane, maybe_name) in query.iter(){
+        if trigger.plane_entity.is_some_and(|entity| entity != plane_entity) {
+            continue;
+        }
+        let path = match settings.path_for(plane_entity, maybe_name) {
+            Ok(path) => {path}
+            Err(e) => {
+                error!("refused to save terrain {:?}: {}", plane_entity, e);
+                commands.trigger(TerrainSaved{plane_entity, path: settings.directory.clone(), result: Err(e.to_string())});
+                continue;
+            }
+        };
+        let result: Result<(), String> = match meshes.get(&mesh3d.0) {
+            Some(mesh) => save_mesh_to_file(&saved_mesh(mesh, plane), &path).map_err(|e| e.to_string()),
+            None => Err(String::from("plane mesh asset is not loaded"))
+        };
+        if let Err(e) = &result {
+            error!("failed to save terrain {:?} to {:?}: {}", plane_entity, path, e);
+        }
+        commands.trigger(TerrainSaved{plane_entity, path, result});
+    }
+}
+
+#[derive(Event)]
+pub struct LoadTerrain {
+    pub path: PathBuf,
+    pub transform: Transform
+}
+impl LoadTerrain {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        LoadTerrain {
+            path: path.into(),
+            transform: Transform::default()
+        }
+    }
+}
+
+#[derive(Event, Debug)]
+pub struct TerrainLoaded {
+    pub path: PathBuf,
+    pub result: Result<Entity, String>
+}
+
+pub fn load_plane_from_file(path: &Path) -> std::io::Result<(Mesh, PlaneToEdit)> {
+    let mesh = load_mesh_from_file(&path.to_string_lossy())?;
+    let Some(plane) = PlaneToEdit::from_mesh(&mesh) else {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "mesh is not a subdivided plane"));
+    };
+    return Ok((mesh, plane));
+}
+
+pub(crate) fn load_terrain(
+    trigger:       On<LoadTerrain>,
+    mut commands:  Commands,
+    mut meshes:    ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>
+){
+    let path = trigger.path.clone();
+    let (mesh, plane) = match load_plane_from_file(&path) {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            error!("failed to load terrain from {:?}: {}", path, e);
+            commands.trigger(TerrainLoaded{path, result: Err(e.to_string())});
+            return;
+        }
+    };
+    let name: String = path.file_stem().map(|stem| stem.to_string_lossy().to_string()).unwrap_or_default();
+
+    let plane_entity = commands.spawn((
+        Mesh3d(meshes.add(mesh)),
+        plane,
+        TerrainName(name),
+        MeshMaterial3d(materials.add(StandardMaterial::from_color(Color::WHITE))),
+        trigger.transform
+    )).id();
+
+    commands.trigger(SpawnVertices{plane_entity});
+    commands.trigger(TerrainLoaded{path, result: Ok(plane_entity)});
+}
+
+// Chunked terrain file, chunk meshes are stored next to it and listed relative to it in layout.chunk_index order
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ChunkManifest {
+    pub layout: ChunkLayout,
+    pub chunks: Vec<String>
+}
+
+pub fn save_chunks_to_file(layout: &ChunkLayout, chunk_meshes: &[&Mesh], manifest_path: &Path) -> std::io::Result<()> {
+    if chunk_meshes.len() != layout.chunk_count() {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "chunk count does not match the layout"));
+    }
+    let directory = manifest_path.parent().unwrap_or(Path::new(""));
+    let file_name = manifest_path.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+    let stem = file_name.strip_suffix(".chunks.json").unwrap_or(&file_name).to_string();
+
+    let mut manifest = ChunkManifest {
+        layout: *layout,
+        chunks: Vec::with_capacity(chunk_meshes.len())
+    };
+    for (chunk_index, mesh) in chunk_meshes.iter().enumerate(){
+        let chunk_file = format!("{}/chunk_{}_{}.json", stem, chunk_index % layout.chunks_x, chunk_index / layout.chunks_x);
+        save_mesh_to_file(mesh, &directory.join(&chunk_file))?;
+        manifest.chunks.push(chunk_file);
+    }
+    let json = serde_json::to_string_pretty(&manifest)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+    std::fs::write(manifest_path, json)?;
+    return Ok(());
+}
+
+pub fn load_chunks_from_file(manifest_path: &Path) -> std::io::Result<(ChunkLayout, Vec<(Mesh, PlaneToEdit)>)> {
+    let json = std::fs::read_to_string(manifest_path)?;
+    let manifest: ChunkManifest = serde_json::from_str(&json)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+    if manifest.chunks.len() != manifest.layout.chunk_count() {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "chunk count does not match the layout"));
+    }
+    let directory = manifest_path.parent().unwrap_or(Path::new(""));
+    let mut chunks: Vec<(Mesh, PlaneToEdit)> = Vec::with_capacity(manifest.chunks.len());
+    for chunk_file in manifest.chunks.iter(){
+        let (mesh, plane) = load_plane_from_file(&directory.join(chunk_file))?;
+        if plane.grid_size() != manifest.layout.chunk_grid_size() {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("{} does not match the chunk layout", chunk_file)));
+        }
+        chunks.push((mesh, plane));
+    }
+    return Ok((manifest.layout, chunks));
+}
+
+// Saves the given chunked terrain, or every ChunkedTerrain when terrain_entity is None
+#[derive(Event)]
+pub struct SaveChunkedTerrain {
+    pub terrain_entity: Option<Entity>
+}
+impl SaveChunkedTerrain {
+    pub fn all() -> Self {
+        SaveChunkedTerrain { terrain_entity: None }
+    }
+    pub fn terrain(terrain_entity: Entity) -> Self {
+        SaveChunkedTerrain { terrain_entity: Some(terrain_entity) }
+    }
+}
+
+#[derive(Event, Debug)]
+pub struct ChunkedTerrainSaved {
+    pub terrain_entity: Entity,
+    pub path: PathBuf,
+    pub result: Result<(), String>
+}
+
+pub(crate) fn save_chunked_terrain(
+    trigger:      On<SaveChunkedTerrain>,
+    mut commands: Commands,
+    settings:     Res<TerrainSaveSettings>,
+    meshes:       Res<Assets<Mesh>>,
+    terrains:     Query<(Entity, &ChunkedTerrain, Option<&TerrainName>)>,
+    chunks:       Query<(&Mesh3d, &PlaneToEdit), With<TerrainChunk>>
+){
+    for (terrain_entity, terrain, maybe_name) in terrains.iter(){
+        if trigger.terrain_entity.is_some_and(|entity| entity != terrain_entity) {
+            continue;
+        }
+        let path = match settings.manifest_path_for(terrain_entity, maybe_name) {
+            Ok(path) => {path}
+            Err(e) => {
+                error!("refused to save chunked terrain {:?}: {}", terrain_entity, e);
+                commands.trigger(ChunkedTerrainSaved{terrain_entity, path: settings.directory.clone(), result: Err(e.to_string())});
+                continue;
+            }
+        };
+        let chunk_meshes: Option<Vec<Cow<Mesh>>> = terrain.chunks
+            .iter()
+            .map(|chunk_entity| {
+                let (mesh3d, plane) = chunks.get(*chunk_entity).ok()?;
+                return meshes.get(&mesh3d.0).map(|mesh| saved_mesh(mesh, plane));
+            })
+            .collect();
+        let result: Result<(), String> = match chunk_meshes {
+            Some(chunk_meshes) => {
+                let chunk_meshes: Vec<&Mesh> = chunk_meshes.iter().map(|mesh| mesh.as_ref()).collect();
+                save_chunks_to_file(&terrain.layout, &chunk_meshes, &path).map_err(|e| e.to_string())
+            }
+            None => Err(String::from("chunk mesh asset is not loaded"))
+        };
+        if let Err(e) = &result {
+            error!("failed to save chunked terrain {:?} to {:?}: {}", terrain_entity, path, e);
+        }
+        commands.trigger(ChunkedTerrainSaved{terrain_entity, path, result});
+    }
+}
+
+#[derive(Event)]
+pub struct LoadChunkedTerrain {
+    pub path: PathBuf,
+    pub transform: Transform
+}
+impl LoadChunkedTerrain {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        LoadChunkedTerrain {
+            path: path.into(),
+            transform: Transform::default()
+        }
+    }
+}
+
+#[derive(Event, Debug)]
+pub struct ChunkedTerrainLoaded {
+    pub path: PathBuf,
+    pub result: Result<Entity, String>
+}
+
+pub(crate) fn load_chunked_terrain(
+    trigger:       On<LoadChunkedTerrain>,
+    mut commands:  Commands,
+    mut meshes:    ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>
+){
+    let path = trigger.path.clone();
+    let (layout, chunks) = match load_chunks_from_file(&path) {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            error!("failed to load chunked terrain from {:?}: {}", path, e);
+            commands.trigger(ChunkedTerrainLoaded{path, result: Err(e.to_string())});
+            return;
+        }
+    };
+    let file_name: String = path.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+    let name: String = file_name.strip_suffix(".chunks.json").unwrap_or(&file_name).to_string();
+
+    // Chunks get no vertex markers, a large terrain would spawn one entity per vertex
+    let chunk_bundles: Vec<(Mesh3d, PlaneToEdit)> = chunks
+        .into_iter()
+        .map(|(mesh, plane)| (Mesh3d(meshes.add(mesh)), plane))
+        .collect();
+    let material = MeshMaterial3d(materials.add(StandardMaterial::from_color(Color::WHITE)));
+    let terrain_entity = spawn_chunks(&mut commands, layout, chunk_bundles, material);
+    commands.entity(terrain_entity).insert((TerrainName(name), trigger.transform));
+
+    commands.trigger(ChunkedTerrainLoaded{path, result: Ok(terrain_entity)});
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy::ecs::system::RunSystemOnce;
+    use crate::planes::plane_mesh;
+
+    #[test]
+    fn save_load_save_round_trip() {
+        let mut world = World::new();
+        world.init_resource::<Assets<Mesh>>();
+        world.run_system_once(|mut commands: Commands, mut meshes: ResMut<Assets<Mesh>>| {
+            commands.spawn(plane_mesh(12.0, 8.0, 5, &mut meshes));
+        }).unwrap();
+        let mesh3d = world.query::<&Mesh3d>().single(&world).unwrap().clone();
+        let mesh = world.resource::<Assets<Mesh>>().get(&mesh3d.0).unwrap().clone();
+
+        let directory = std::env::temp_dir().join(format!("terrain_round_trip_{}", std::process::id()));
+        let first = directory.join("first.json");
+        let second = directory.join("second.json");
+        save_mesh_to_file(&mesh, &first).unwrap();
+        let (loaded, plane) = load_plane_from_file(&first).unwrap();
+        save_mesh_to_file(&loaded, &second).unwrap();
+
+        assert_eq!(std::fs::read(&first).unwrap(), std::fs::read(&second).unwrap());
+        assert_eq!(plane.width, 12.0);
+        assert_eq!(plane.height, 8.0);
+        assert_eq!(plane.subdivisions, 5);
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn names_must_stay_in_the_save_directory() {
+        for name in ["hills", "hills.v2", "..hills"] {
+            assert!(TerrainName(String::from(name)).check().is_ok(), "{name}");
+        }
+        for name in ["", ".", "..", "../hills", "maps/hills", "/tmp/hills", "maps\\hills"] {
+            assert!(TerrainName(String::from(name)).check().is_err(), "{name}");
+        }
+        let settings = TerrainSaveSettings::new("saves");
+        assert_eq!(settings.path_for(Entity::PLACEHOLDER, Some(&TerrainName(String::from("hills")))).unwrap(), PathBuf::from("saves/hills.json"));
+        assert!(settings.manifest_path_for(Entity::PLACEHOLDER, Some(&TerrainName(String::from("../hills")))).is_err());
+    }
+
+    #[test]
+    fn rejected_name_is_reported() {
+        let directory = std::env::temp_dir().join(format!("terrain_rejected_name_{}", std::process::id()));
+        let mut world = World::new();
+        world.init_resource::<Assets<Mesh>>();
+        world.insert_resource(TerrainSaveSettings::new(&directory));
+        world.add_observer(save_terrain);
+        world.add_observer(|trigger: On<TerrainSaved>, mut commands: Commands| {
+            commands.insert_resource(LastSaved(trigger.result.clone()));
+        });
+        world.run_system_once(|mut commands: Commands, mut meshes: ResMut<Assets<Mesh>>| {
+            commands.spawn((plane_mesh(2.0, 2.0, 1, &mut meshes), TerrainName(String::from("../escaped"))));
+        }).unwrap();
+
+        world.trigger(SaveTerrain::all());
+        world.flush();
+        assert!(world.resource::<LastSaved>().0.is_err());
+        assert!(!directory.exists());
+        assert!(!std::env::temp_dir().join("escaped.json").exists());
+    }
+
+    #[derive(Resource)]
+    struct LastSaved(Result<(), String>);
+}
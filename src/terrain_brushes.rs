@@ -1,10 +1,23 @@
 use bevy::prelude::*;
 use bevy_pg_editor_tools::prelude::BrushType;
 use bevy::ecs::system::SystemState;
+use bevy::tasks::{ComputeTaskPool, ParallelSlice, TaskPool};
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
 
-use crate::prelude::{PlaneVertex, SelectedVertex, Noise};
+use crate::prelude::{PlaneToEdit, VertexRefs, Noise, NoiseExpr, Falloff};
+use crate::planes::{plane_distance, world_height, local_height, fit_plane_bounds, grid_neighbours};
+use crate::heightfield::TerrainHeightfield;
+use crate::chunks::{ChunkedTerrain, TerrainChunk};
+use crate::history::{BrushHistory, VertexSnapshot};
+use crate::erosion::{Erosion, HydraulicErosion, ThermalErosion, erode_vertices};
 
-#[derive(Clone)]
+// Brush strokes touching at least this many vertices are computed on the ComputeTaskPool
+pub(crate) const PARALLEL_THRESHOLD: usize = 4096;
+pub(crate) const PARALLEL_CHUNK_SIZE: usize = 1024;
+
+// Terraces, flatten targets and color ranges are world heights, so planes of any transform line up
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Terrace {
     pub min: f32,
     pub max: f32,
@@ -16,157 +29,743 @@ impl Terrace {
     }
 }
 
-#[derive(Clone)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum HeightBrushType {
     Value(f32),
     Terraces(Vec<Terrace>),
-    Noise((Vec<Noise>, f32))
+    Noise((Vec<Noise>, f32)),
+    Expression(NoiseExpr),
+    Smooth{strength: f32, iterations: usize},
+    Flatten{target: FlattenTarget, strength: f32},
+    HydraulicErosion(HydraulicErosion),
+    ThermalErosion(ThermalErosion)
+}
+
+impl HeightBrushType {
+    // Variants that need the heights of the whole plane grid
+    fn uses_grid(&self) -> bool {
+        match self {
+            HeightBrushType::Smooth{..} | HeightBrushType::HydraulicErosion(_) | HeightBrushType::ThermalErosion(_) => {return true;}
+            _ => {return false;}
+        }
+    }
 }
 
-#[derive(Clone)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FlattenTarget {
+    Value(f32),
+    StrokeStart
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct TerrainHeightBrush {
     pub typ: HeightBrushType,
-    pub reselection: bool
+    #[serde(default = "default_reselection")]
+    pub reselection: bool,
+    #[serde(default)]
+    pub falloff: Falloff,
+    #[serde(skip)]
+    pub stroke_height: Option<f32> // world height under the brush when the stroke started
 }
 
-impl BrushType for TerrainHeightBrush {
-    fn apply(&mut self, world: &mut World, loc: Vec3, radius: f32) {
+fn default_reselection() -> bool {
+    return true;
+}
+
+impl TerrainHeightBrush {
+    pub fn new(typ: HeightBrushType) -> Self {
+        TerrainHeightBrush {
+            typ,
+            reselection: true,
+            falloff: Falloff::Constant,
+            stroke_height: None
+        }
+    }
+
+    // New height of a vertex for the brush types that only read that vertex, None leaves it as is.
+    // local_offset is added to the plane space position local noises sample, chunks pass their offset in the terrain.
+    fn vertex_height(
+        &self,
+        heightfield:     &TerrainHeightfield,
+        plane_transform: &GlobalTransform,
+        local_offset:    Vec3,
+        index:           usize,
+        weight:          f32
+    ) -> Option<f32> {
+        let local_pos = heightfield.local_position(index);
+        let y: f32 = local_pos.y;
+        match &self.typ {
+            HeightBrushType::Value(value) => {
+                return Some(y + value*weight);
+            }
+            HeightBrushType::Terraces(terraces) => {
+                // The last matching terrace wins
+                let world_y: f32 = world_height(plane_transform, local_pos);
+                let terrace = terraces.iter().rev().find(|terrace| world_y >= terrace.min && world_y <= terrace.max)?;
+                let target_y: f32 = local_height(plane_transform, local_pos, terrace.value)?;
+                return Some(y + (target_y - y)*weight);
+            }
+            HeightBrushType::Noise(noises) => {
+                let global_loc: Vec3 = plane_transform.transform_point(local_pos);
+                let mut combined_noise: f32 = 0.0;
+                for noise in noises.0.iter(){
+                    let noise_value = noise.sample(global_loc, local_pos + local_offset);
+                    combined_noise += noise_value;
+                }
+                let new_y: f32 = combined_noise*noises.1;
+                return Some(y + (new_y - y)*weight);
+            }
+            HeightBrushType::Expression(expr) => {
+                let global_loc: Vec3 = plane_transform.transform_point(local_pos);
+                let new_y: f32 = expr.sample(global_loc, local_pos + local_offset);
+                return Some(y + (new_y - y)*weight);
+            }
+            HeightBrushType::Flatten{target, strength} => {
+                let target_world_y: f32 = match target {
+                    FlattenTarget::Value(value) => *value,
+                    FlattenTarget::StrokeStart => self.stroke_height?
+                };
+                let target_y: f32 = local_height(plane_transform, local_pos, target_world_y)?;
+                return Some(y + (target_y - y)*strength.clamp(0.0, 1.0)*weight);
+            }
+            HeightBrushType::Smooth{..} | HeightBrushType::HydraulicErosion(_) | HeightBrushType::ThermalErosion(_) => {
+                return None;
+            }
+        }
+    }
 
-        let mut system_state: SystemState<(
-            Commands,
-            Query<(Entity, &mut PlaneVertex, &mut Transform, &GlobalTransform, Option<&SelectedVertex>)>
-        )> = SystemState::new(world);
-        let (mut commands, mut plane_vertices) = system_state.get_mut(world);
+    // New heights for (index, weight) targets. Every vertex only reads the heightfield,
+    // so the parallel path returns exactly what the serial one does.
+    pub fn vertex_heights(
+        &self,
+        heightfield:     &TerrainHeightfield,
+        plane_transform: &GlobalTransform,
+        local_offset:    Vec3,
+        targets:         &[(usize, f32)],
+        parallel:        bool
+    ) -> Vec<Option<f32>> {
+        if !parallel {
+            return targets
+                .iter()
+                .map(|(index, weight)| self.vertex_height(heightfield, plane_transform, local_offset, *index, *weight))
+                .collect();
+        }
+        let task_pool = ComputeTaskPool::get_or_init(TaskPool::default);
+        return targets
+            .par_chunk_map(task_pool, PARALLEL_CHUNK_SIZE, |_, chunk| {
+                chunk
+                    .iter()
+                    .map(|(index, weight)| self.vertex_height(heightfield, plane_transform, local_offset, *index, *weight))
+                    .collect::<Vec<Option<f32>>>()
+            })
+            .into_iter()
+            .flatten()
+            .collect();
+    }
+}
 
-        for (vertex_entity, mut plane_vertex, mut vertex_transform, global_transform, maybe_selected) in plane_vertices.iter_mut(){
+impl TerrainHeightBrush {
+    // Runs the grid modes on a cols x rows height grid, only the targets are meant to be written back
+    fn grid_heights(&self, heights: &mut [f32], cols: usize, rows: usize, cell_size: Vec2, targets: &[(usize, f32)]) {
+        match &self.typ {
+            HeightBrushType::Smooth{strength, iterations} => {
+                let grid_indices: Vec<(usize, f32)> = targets.iter().map(|(index, weight)| (*index, strength*weight)).collect();
+                smooth_heights(heights, cols, rows, &grid_indices, *iterations, grid_indices.len() >= PARALLEL_THRESHOLD);
+            }
+            HeightBrushType::HydraulicErosion(params) => {
+                erode_vertices(heights, cols, rows, cell_size, targets, &Erosion::Hydraulic(params.clone()));
+            }
+            HeightBrushType::ThermalErosion(params) => {
+                erode_vertices(heights, cols, rows, cell_size, targets, &Erosion::Thermal(params.clone()));
+            }
+            _ => {}
+        }
+    }
+}
+
+// Planes a height brush edits, chunks carry their TerrainChunk
+type HeightBrushPlanes<'w, 's> = Query<'w, 's, (Entity, &'static PlaneToEdit, &'static GlobalTransform, &'static mut TerrainHeightfield, Option<&'static TerrainChunk>)>;
+
+type HeightBrushState = SystemState<(
+    HeightBrushPlanes<'static, 'static>,
+    Query<'static, 'static, &'static ChunkedTerrain>,
+    Option<Res<'static, VertexRefs>>,
+    Option<ResMut<'static, BrushHistory>>
+)>;
+
+// Weighted targets of every chunk, grouped by the terrain they belong to
+type ChunkTargets = HashMap<Entity, Vec<(Entity, Vec<(usize, f32)>)>>;
+
+// Heights of the whole chunked terrain, every global vertex read from its owner chunk
+fn global_heights(
+    terrain: &ChunkedTerrain,
+    planes:  &HeightBrushPlanes
+) -> Option<Vec<f32>> {
+    if terrain.chunks.len() != terrain.layout.chunk_count() {
+        return None;
+    }
+    let (chunk_cols, chunk_rows) = terrain.layout.chunk_grid_size();
+    let mut chunk_heights: Vec<&[f32]> = Vec::with_capacity(terrain.chunks.len());
+    for chunk_entity in terrain.chunks.iter(){
+        let (_, _, _, heightfield, _) = planes.get(*chunk_entity).ok()?;
+        if heightfield.len() != chunk_cols*chunk_rows {
+            return None;
+        }
+        chunk_heights.push(&heightfield.heights);
+    }
+    let (cols, rows) = terrain.layout.grid_size();
+    let mut heights: Vec<f32> = Vec::with_capacity(cols*rows);
+    for gz in 0..rows {
+        for gx in 0..cols {
+            let (chunk_index, index) = terrain.layout.owner(gx, gz);
+            heights.push(chunk_heights[chunk_index][index]);
+        }
+    }
+    return Some(heights);
+}
 
-            let global_loc = global_transform.translation();
-            let near: bool = global_loc.xz().distance(loc.xz()) <= (radius + plane_vertex.radius);
+impl BrushType for TerrainHeightBrush {
+    fn apply(&mut self, world: &mut World, loc: Vec3, radius: f32) {
 
-            if near & maybe_selected.is_none() {
-                commands.entity(vertex_entity).insert(SelectedVertex);
+        let mut system_state: HeightBrushState = SystemState::new(world);
+        let (mut planes, terrains, vertex_refs, mut history) = system_state.get_mut(world);
+        let reach: f32 = radius + vertex_refs.map(|refs| refs.radius).unwrap_or(0.0);
+        let mut chunk_targets: ChunkTargets = HashMap::new();
 
-                match &self.typ {
-                    HeightBrushType::Value(y) => {
-                        vertex_transform.translation.y += y;
+        if matches!(&self.typ, HeightBrushType::Flatten{target: FlattenTarget::StrokeStart, ..}) && self.stroke_height.is_none() {
+            let mut closest: Option<(f32, f32)> = None;
+            for (_, _, plane_transform, heightfield, _) in planes.iter(){
+                let local_loc = plane_transform.affine().inverse().transform_point3(loc);
+                for (index, distance) in heightfield.indices_within(plane_transform, local_loc, reach){
+                    if closest.is_none_or(|(closest_distance, _)| distance < closest_distance) {
+                        closest = Some((distance, world_height(plane_transform, heightfield.local_position(index))));
                     }
-                    HeightBrushType::Terraces(terraces) => {
-                        for terrace in terraces {
-                            if vertex_transform.translation.y >= terrace.min && vertex_transform.translation.y <= terrace.max {
-                                vertex_transform.translation.y = terrace.value;
-                                continue;
-                            }
-                        }
+                }
+            }
+            self.stroke_height = closest.map(|(_, height)| height);
+        }
+
+        for (plane_entity, plane, plane_transform, mut heightfield, chunk) in planes.iter_mut(){
+            let local_loc = plane_transform.affine().inverse().transform_point3(loc);
+            let local_offset: Vec3 = chunk.map(|chunk| chunk.offset).unwrap_or(Vec3::ZERO);
+
+            if self.reselection {
+                heightfield.retain_selection(|local_pos| plane_distance(plane_transform, local_loc, local_pos) <= reach);
+            }
+            // Vertices entering the brush are edited once per selection
+            let mut targets: Vec<(usize, f32)> = Vec::new();
+            for (index, distance) in heightfield.indices_within(plane_transform, local_loc, reach){
+                if heightfield.select(index) {
+                    if let Some(history) = history.as_mut() {
+                        history.record(VertexSnapshot::new(plane_entity, index, &heightfield));
                     }
-                    HeightBrushType::Noise(noises) => {
-                        let mut combined_noise: f32 = 0.0;
-                        for noise in noises.0.iter(){
-                            let noise_value = noise.apply(global_loc);
-                            combined_noise += noise_value;
-                        }
-                        let new_y: f32 = combined_noise*noises.1;
-                        vertex_transform.translation.y = new_y;
+                    targets.push((index, self.falloff.sample(distance/reach)));
+                }
+            }
+            if targets.is_empty() {
+                continue;
+            }
+
+            if !self.typ.uses_grid() {
+                let heights = self.vertex_heights(&heightfield, plane_transform, local_offset, &targets, targets.len() >= PARALLEL_THRESHOLD);
+                for ((index, _), height) in targets.iter().zip(heights){
+                    if let Some(height) = height {
+                        heightfield.set_height(*index, height);
                     }
                 }
+                continue;
+            }
+
+            // Chunks share their edges, so grid modes run once on the global grid of their terrain
+            if let Some(chunk) = chunk {
+                chunk_targets.entry(chunk.terrain_entity).or_default().push((plane_entity, targets));
+                continue;
+            }
+            if heightfield.len() != plane.vertex_count() {
+                continue;
+            }
+            let (cols, rows) = plane.grid_size();
+            let mut heights: Vec<f32> = heightfield.heights.clone();
+            self.grid_heights(&mut heights, cols, rows, plane.cell_size(), &targets);
+            for (index, _) in targets.iter(){
+                heightfield.set_height(*index, heights[*index]);
+            }
+        }
+
+        for (terrain_entity, targets) in chunk_targets.iter(){
+            let Ok(terrain) = terrains.get(*terrain_entity) else {continue;};
+            let layout = terrain.layout;
+            let (cols, rows) = layout.grid_size();
+            let Some(mut heights) = global_heights(terrain, &planes) else {continue;};
 
-                plane_vertex.loc = vertex_transform.translation.into();
-            } else if  near & maybe_selected.is_some() {
+            // A seam vertex is a target in every chunk holding it, the global grid edits it once
+            let mut global_targets: HashMap<usize, f32> = HashMap::new();
+            for (chunk_entity, chunk_targets) in targets.iter(){
+                let Ok((_, _, _, _, Some(chunk))) = planes.get(*chunk_entity) else {continue;};
+                for (index, weight) in chunk_targets.iter(){
+                    let (gx, gz) = layout.global_coords(chunk.x, chunk.z, *index);
+                    let global_weight = global_targets.entry(gz*cols + gx).or_insert(0.0);
+                    *global_weight = global_weight.max(*weight);
+                }
+            }
+            let mut global_targets: Vec<(usize, f32)> = global_targets.into_iter().collect();
+            global_targets.sort_by_key(|(index, _)| *index);
+            self.grid_heights(&mut heights, cols, rows, layout.cell_size(), &global_targets);
 
-            } else {
-                if self.reselection {
-                    commands.entity(vertex_entity).remove::<SelectedVertex>();
+            for (global_index, _) in global_targets.iter(){
+                for (chunk_entity, index) in terrain.copies(global_index % cols, global_index / cols){
+                    let Ok((_, _, _, mut heightfield, _)) = planes.get_mut(chunk_entity) else {continue;};
+                    if let Some(history) = history.as_mut() {
+                        history.record(VertexSnapshot::new(chunk_entity, index, &heightfield));
+                    }
+                    heightfield.set_height(index, heights[*global_index]);
                 }
             }
         }
         system_state.apply(world);
     }
     fn done(&mut self, world: &mut World) {
-        let mut system_state: SystemState<(
-            Commands,
-            Query<Entity, With<SelectedVertex>>
-        )> = SystemState::new(world);
-        let (mut commands, plane_vertices) = system_state.get_mut(world);
-        for entity in plane_vertices.iter(){
-            commands.entity(entity).remove::<SelectedVertex>();
+        clear_selection(world);
+        fit_plane_bounds(world);
+        if let Some(mut history) = world.get_resource_mut::<BrushHistory>() {
+            history.end_stroke();
+        }
+    }
+    fn started(&mut self, world: &mut World) {
+        self.stroke_height = None;
+        if let Some(mut history) = world.get_resource_mut::<BrushHistory>() {
+            history.begin_stroke();
+        }
+    }
+}
+
+// indices are (vertex index, strength) pairs of a cols x rows grid. Every iteration only reads the previous one,
+// so the parallel path returns exactly what the serial one does.
+pub fn smooth_heights(
+    heights:    &mut [f32],
+    cols:       usize,
+    rows:       usize,
+    indices:    &[(usize, f32)],
+    iterations: usize,
+    parallel:   bool
+){
+    let smooth_vertex = |heights: &[f32], (index, strength): &(usize, f32)| -> f32 {
+        if *index >= cols*rows || *index >= heights.len() {
+            return heights.get(*index).copied().unwrap_or(0.0);
+        }
+        let mut sum: f32 = 0.0;
+        let mut count: usize = 0;
+        for neighbour in grid_neighbours(cols, rows, *index) {
+            sum += heights[neighbour];
+            count += 1;
+        }
+        if count == 0 {
+            return heights[*index];
+        }
+        let average: f32 = sum/count as f32;
+        return heights[*index] + (average - heights[*index])*strength.clamp(0.0, 1.0);
+    };
+    for _ in 0..iterations {
+        let smoothed: Vec<f32> = if parallel {
+            let task_pool = ComputeTaskPool::get_or_init(TaskPool::default);
+            let current: &[f32] = heights;
+            indices
+                .par_chunk_map(task_pool, PARALLEL_CHUNK_SIZE, |_, chunk| {
+                    chunk.iter().map(|target| smooth_vertex(current, target)).collect::<Vec<f32>>()
+                })
+                .into_iter()
+                .flatten()
+                .collect()
+        } else {
+            indices.iter().map(|target| smooth_vertex(heights, target)).collect()
+        };
+        for ((index, _), height) in indices.iter().zip(smoothed){
+            if let Some(h) = heights.get_mut(*index){
+                *h = height;
+            }
         }
-        system_state.apply(world);
     }
-    fn started(&mut self, world: &mut World) {}
 }
 
-#[derive(Clone)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct TerrainColorBrush {
-    pub typ: ColorBrushType
+    pub typ: ColorBrushType,
+    #[serde(default)]
+    pub falloff: Falloff
 }
 
-#[derive(Clone)]
+impl TerrainColorBrush {
+    pub fn new(typ: ColorBrushType) -> Self {
+        TerrainColorBrush {
+            typ,
+            falloff: Falloff::Constant
+        }
+    }
+
+    // New color of a vertex, None leaves it as is
+    fn vertex_color(
+        &self,
+        heightfield:     &TerrainHeightfield,
+        plane_transform: &GlobalTransform,
+        local_offset:    Vec3,
+        index:           usize,
+        weight:          f32
+    ) -> Option<[f32;4]> {
+        let local_pos = heightfield.local_position(index);
+        let clr: [f32;4] = heightfield.colors[index];
+        match &self.typ {
+            ColorBrushType::Value{clr: value_clr} => {return Some(blend_color(&clr, value_clr, weight));}
+            ColorBrushType::Noise { data, value, clr: noise_base_clr} => {
+                let global_loc: Vec3 = plane_transform.transform_point(local_pos);
+                let mut combined_noise: f32 = 0.0;
+                for noise in data.iter(){
+                    let noise_value = noise.sample(global_loc, local_pos + local_offset);
+                    combined_noise += noise_value;
+                }
+                let alpha: f32 = combined_noise*value;
+                let noise_clr = [noise_base_clr[0], noise_base_clr[1], noise_base_clr[2], alpha.clamp(0.0, 1.0)];
+                return Some(blend_color(&clr, &noise_clr, weight));
+            }
+            ColorBrushType::Expression{expr, clr: expr_base_clr} => {
+                let global_loc: Vec3 = plane_transform.transform_point(local_pos);
+                let alpha: f32 = expr.sample(global_loc, local_pos + local_offset);
+                let expr_clr = [expr_base_clr[0], expr_base_clr[1], expr_base_clr[2], alpha.clamp(0.0, 1.0)];
+                return Some(blend_color(&clr, &expr_clr, weight));
+            }
+            ColorBrushType::Range { min, max, min_clr, max_clr } => {
+                let world_y: f32 = world_height(plane_transform, local_pos);
+                if &world_y < min || &world_y > max {
+                    return None;
+                }
+                let norm_y = (world_y - min)/(max-min);
+                let interpolated_clr = [
+                    min_clr[0] + (max_clr[0] - min_clr[0]) * norm_y,
+                    min_clr[1] + (max_clr[1] - min_clr[1]) * norm_y,
+                    min_clr[2] + (max_clr[2] - min_clr[2]) * norm_y,
+                    min_clr[3] + (max_clr[3] - min_clr[3]) * norm_y,
+                ];
+                return Some(blend_color(&clr, &interpolated_clr, weight));
+            }
+        }
+    }
+
+    // New colors for (index, weight) targets, parallel and serial results are identical
+    pub fn vertex_colors(
+        &self,
+        heightfield:     &TerrainHeightfield,
+        plane_transform: &GlobalTransform,
+        local_offset:    Vec3,
+        targets:         &[(usize, f32)],
+        parallel:        bool
+    ) -> Vec<Option<[f32;4]>> {
+        if !parallel {
+            return targets
+                .iter()
+                .map(|(index, weight)| self.vertex_color(heightfield, plane_transform, local_offset, *index, *weight))
+                .collect();
+        }
+        let task_pool = ComputeTaskPool::get_or_init(TaskPool::default);
+        return targets
+            .par_chunk_map(task_pool, PARALLEL_CHUNK_SIZE, |_, chunk| {
+                chunk
+                    .iter()
+                    .map(|(index, weight)| self.vertex_color(heightfield, plane_transform, local_offset, *index, *weight))
+                    .collect::<Vec<Option<[f32;4]>>>()
+            })
+            .into_iter()
+            .flatten()
+            .collect();
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum ColorBrushType {
     Value{clr: [f32;4]},
     Range{min: f32, max: f32, min_clr: [f32;4], max_clr: [f32;4]},
-    Noise{data: Vec<Noise>, value: f32, clr: [f32;4]}
+    Noise{data: Vec<Noise>, value: f32, clr: [f32;4]},
+    Expression{expr: NoiseExpr, clr: [f32;4]}
 }
 
 
+type ColorBrushState = SystemState<(
+    Query<'static, 'static, (Entity, &'static GlobalTransform, &'static mut TerrainHeightfield, Option<&'static TerrainChunk>)>,
+    Option<Res<'static, VertexRefs>>,
+    Option<ResMut<'static, BrushHistory>>
+)>;
+
 impl BrushType for TerrainColorBrush {
     fn apply(&mut self, world: &mut World, loc: Vec3, radius: f32) {
-        let mut system_state: SystemState<(
-            Commands,
-            Query<(Entity, &mut PlaneVertex, &GlobalTransform, Option<&SelectedVertex>)>
-        )> = SystemState::new(world);
-        let (mut commands, mut plane_vertices) = system_state.get_mut(world);
-
-        for (vertex_entity, mut plane_vertex, global_transform, maybe_selected) in plane_vertices.iter_mut(){
-
-            let global_loc = global_transform.translation();
-            let near: bool = global_loc.xz().distance(loc.xz()) <= (radius + plane_vertex.radius);
-
-            if near & maybe_selected.is_none() {
-                commands.entity(vertex_entity).insert(SelectedVertex);
-                match &self.typ {
-                    ColorBrushType::Value{clr} => {plane_vertex.clr = *clr;}
-                    ColorBrushType::Noise { data, value, clr} => {
-
-                        let mut combined_noise: f32 = 0.0;
-                        for noise in data.iter(){
-                            let noise_value = noise.apply(global_loc);
-                            combined_noise += noise_value;
-                        }
-                        let alpha: f32 = combined_noise*value;
-                        plane_vertex.clr = [clr[0], clr[1], clr[2], alpha.clamp(0.0, 1.0)];
-                    }
-                    ColorBrushType::Range { min, max, min_clr, max_clr } => {
-                        if &global_loc.y >= min && &global_loc.y <= max {
-                            let norm_y = (global_loc.y - min)/(max-min);
-                            let interpolated_clr = [
-                                min_clr[0] + (max_clr[0] - min_clr[0]) * norm_y,
-                                min_clr[1] + (max_clr[1] - min_clr[1]) * norm_y,
-                                min_clr[2] + (max_clr[2] - min_clr[2]) * norm_y,
-                                min_clr[3] + (max_clr[3] - min_clr[3]) * norm_y,
-                            ];
-                            plane_vertex.clr = interpolated_clr;
-                        }
+        let mut system_state: ColorBrushState = SystemState::new(world);
+        let (mut planes, vertex_refs, mut history) = system_state.get_mut(world);
+        let reach: f32 = radius + vertex_refs.map(|refs| refs.radius).unwrap_or(0.0);
+
+        for (plane_entity, plane_transform, mut heightfield, chunk) in planes.iter_mut(){
+            let local_loc = plane_transform.affine().inverse().transform_point3(loc);
+            let local_offset: Vec3 = chunk.map(|chunk| chunk.offset).unwrap_or(Vec3::ZERO);
+
+            heightfield.retain_selection(|local_pos| plane_distance(plane_transform, local_loc, local_pos) <= reach);
+            let mut targets: Vec<(usize, f32)> = Vec::new();
+            for (index, distance) in heightfield.indices_within(plane_transform, local_loc, reach){
+                if heightfield.select(index) {
+                    if let Some(history) = history.as_mut() {
+                        history.record(VertexSnapshot::new(plane_entity, index, &heightfield));
                     }
+                    targets.push((index, self.falloff.sample(distance/reach)));
                 }
-            } else if  near & maybe_selected.is_some() {
+            }
+            if targets.is_empty() {
+                continue;
+            }
 
-            } else {
-                commands.entity(vertex_entity).remove::<SelectedVertex>();
+            let colors = self.vertex_colors(&heightfield, plane_transform, local_offset, &targets, targets.len() >= PARALLEL_THRESHOLD);
+            for ((index, _), clr) in targets.iter().zip(colors){
+                if let Some(clr) = clr {
+                    heightfield.set_color(*index, clr);
+                }
             }
         }
         system_state.apply(world);
     }
     fn done(&mut self, world: &mut World) {
+        clear_selection(world);
+        fit_plane_bounds(world);
+        if let Some(mut history) = world.get_resource_mut::<BrushHistory>() {
+            history.end_stroke();
+        }
+    }
+    fn started(&mut self, world: &mut World) {
+        if let Some(mut history) = world.get_resource_mut::<BrushHistory>() {
+            history.begin_stroke();
+        }
+    }
+}
 
-        let mut system_state: SystemState<(
-            Commands,
-            Query<Entity, With<SelectedVertex>>
-        )> = SystemState::new(world);
-        let (mut commands, plane_vertices) = system_state.get_mut(world);
-        for entity in plane_vertices.iter(){
-            commands.entity(entity).remove::<SelectedVertex>();
+fn clear_selection(world: &mut World) {
+    let mut system_state: SystemState<Query<&mut TerrainHeightfield>> = SystemState::new(world);
+    let mut planes = system_state.get_mut(world);
+    for mut heightfield in planes.iter_mut(){
+        heightfield.clear_selection();
+    }
+}
+
+fn blend_color(from: &[f32;4], to: &[f32;4], weight: f32) -> [f32;4] {
+    return [
+        from[0] + (to[0] - from[0]) * weight,
+        from[1] + (to[1] - from[1]) * weight,
+        from[2] + (to[2] - from[2]) * weight,
+        from[3] + (to[3] - from[3]) * weight,
+    ];
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::noises::NoiseType;
+
+    fn spawn_plane(world: &mut World, transform: Transform) -> Entity {
+        let plane = PlaneToEdit::new(4.0, 4.0, 3);
+        let heightfield = TerrainHeightfield::new(&plane);
+        return world.spawn((plane, heightfield, GlobalTransform::from(transform))).id();
+    }
+
+    fn world_heights(world: &World, plane_entity: Entity) -> Vec<f32> {
+        let heightfield = world.get::<TerrainHeightfield>(plane_entity).unwrap();
+        let plane_transform = world.get::<GlobalTransform>(plane_entity).unwrap();
+        return (0..heightfield.len()).map(|index| world_height(plane_transform, heightfield.local_position(index))).collect();
+    }
+
+    fn stroke(brush: &mut impl BrushType, world: &mut World, loc: Vec3, radius: f32) {
+        brush.started(world);
+        brush.apply(world, loc, radius);
+        brush.done(world);
+    }
+
+    // 128x128 vertices, enough for several parallel chunks
+    fn parallel_targets() -> (PlaneToEdit, TerrainHeightfield, Vec<(usize, f32)>) {
+        let plane = PlaneToEdit::new(127.0, 127.0, 126);
+        let mut heightfield = TerrainHeightfield::new(&plane);
+        for index in 0..heightfield.len(){
+            let (x, z) = heightfield.coords(index);
+            heightfield.heights[index] = (x as f32*0.3).sin()*4.0 + (z as f32*0.17).cos()*2.0;
         }
-        system_state.apply(world);
+        let targets: Vec<(usize, f32)> = (0..heightfield.len()).map(|index| (index, 1.0 - (index % 7) as f32*0.1)).collect();
+        return (plane, heightfield, targets);
+    }
+
+    #[test]
+    fn parallel_matches_serial() {
+        let (plane, heightfield, targets) = parallel_targets();
+        let plane_transform = GlobalTransform::from(Transform::from_xyz(3.0, 1.0, -2.0));
+        let noise = Noise{typ: NoiseType::FBMPerlin, scale: 0.05, ..Noise::new()};
+
+        let brush = TerrainHeightBrush::new(HeightBrushType::Noise((vec![noise.clone()], 1.0)));
+        assert_eq!(
+            brush.vertex_heights(&heightfield, &plane_transform, Vec3::ZERO, &targets, false),
+            brush.vertex_heights(&heightfield, &plane_transform, Vec3::ZERO, &targets, true)
+        );
 
+        let brush = TerrainColorBrush::new(ColorBrushType::Noise{data: vec![noise], value: 1.0, clr: [0.2, 0.6, 0.2, 1.0]});
+        assert_eq!(
+            brush.vertex_colors(&heightfield, &plane_transform, Vec3::ZERO, &targets, false),
+            brush.vertex_colors(&heightfield, &plane_transform, Vec3::ZERO, &targets, true)
+        );
+
+        let mut serial = heightfield.heights.clone();
+        let mut parallel = heightfield.heights.clone();
+        let (cols, rows) = plane.grid_size();
+        smooth_heights(&mut serial, cols, rows, &targets, 4, false);
+        smooth_heights(&mut parallel, cols, rows, &targets, 4, true);
+        assert_ne!(serial, heightfield.heights);
+        assert_eq!(serial, parallel);
+    }
+
+    #[test]
+    fn flatten_value_is_a_world_height() {
+        let mut world = World::new();
+        let plane_entity = spawn_plane(&mut world, Transform::from_xyz(0.0, 1.0, 0.0).with_scale(Vec3::new(1.0, 2.0, 1.0)));
+        stroke(&mut TerrainHeightBrush::new(HeightBrushType::Flatten{target: FlattenTarget::Value(3.0), strength: 1.0}), &mut world, Vec3::ZERO, 10.0);
+        for height in world_heights(&world, plane_entity){
+            assert!((height - 3.0).abs() < 1e-5);
+        }
+        assert!(world.get::<TerrainHeightfield>(plane_entity).unwrap().heights.iter().all(|h| (h - 1.0).abs() < 1e-5));
+    }
+
+    #[test]
+    fn stroke_start_height_is_shared_between_planes() {
+        let mut world = World::new();
+        let low = spawn_plane(&mut world, Transform::from_xyz(-2.0, 0.0, 0.0));
+        let high = spawn_plane(&mut world, Transform::from_xyz(2.0, 2.0, 0.0).with_rotation(Quat::from_rotation_y(0.5)));
+        // The stroke starts over the low plane, both planes end at its height
+        let mut brush = TerrainHeightBrush::new(HeightBrushType::Flatten{target: FlattenTarget::StrokeStart, strength: 1.0});
+        brush.started(&mut world);
+        brush.apply(&mut world, Vec3::new(-3.0, 0.0, 0.0), 0.5);
+        brush.apply(&mut world, Vec3::new(2.0, 0.0, 0.0), 10.0);
+        brush.done(&mut world);
+        for plane_entity in [low, high] {
+            for height in world_heights(&world, plane_entity){
+                assert!(height.abs() < 1e-5);
+            }
+        }
+    }
+
+    #[test]
+    fn terraces_and_color_ranges_use_world_heights() {
+        let mut world = World::new();
+        let plane_entity = spawn_plane(&mut world, Transform::from_xyz(0.0, 5.0, 0.0));
+        let terrace = Terrace{min: 4.0, max: 6.0, value: 5.5};
+        stroke(&mut TerrainHeightBrush::new(HeightBrushType::Terraces(vec![Terrace::new(), terrace])), &mut world, Vec3::ZERO, 10.0);
+        assert!(world_heights(&world, plane_entity).iter().all(|height| (height - 5.5).abs() < 1e-5));
+
+        let clr = [0.0, 1.0, 0.0, 1.0];
+        stroke(&mut TerrainColorBrush::new(ColorBrushType::Range{min: 5.0, max: 6.0, min_clr: clr, max_clr: clr}), &mut world, Vec3::ZERO, 10.0);
+        assert!(world.get::<TerrainHeightfield>(plane_entity).unwrap().colors.iter().all(|color| *color == clr));
+    }
+
+    // Serializing what was deserialized gives back the same json
+    fn assert_round_trip<T: Serialize + for<'de> Deserialize<'de>>(value: &T) -> String {
+        let json = serde_json::to_string(value).unwrap();
+        let back: T = serde_json::from_str(&json).unwrap();
+        assert_eq!(json, serde_json::to_string(&back).unwrap());
+        return json;
+    }
+
+    #[test]
+    fn height_brush_types_round_trip() {
+        let noise = Noise{typ: NoiseType::FBMSS, ..Noise::new()};
+        let types = [
+            HeightBrushType::Value(1.5),
+            HeightBrushType::Terraces(vec![Terrace::new(), Terrace{min: 1.0, max: 2.0, value: 1.5}]),
+            HeightBrushType::Noise((vec![noise.clone()], 2.0)),
+            HeightBrushType::Expression(NoiseExpr::Noise(noise)),
+            HeightBrushType::Smooth{strength: 0.5, iterations: 3},
+            HeightBrushType::Flatten{target: FlattenTarget::StrokeStart, strength: 0.25},
+            HeightBrushType::Flatten{target: FlattenTarget::Value(2.0), strength: 1.0},
+            HeightBrushType::HydraulicErosion(HydraulicErosion::new()),
+            HeightBrushType::ThermalErosion(ThermalErosion::new())
+        ];
+        for typ in types.iter(){
+            assert_round_trip(typ);
+        }
+        let json = assert_round_trip(&types[2]);
+        assert!(json.contains("\"fbm_super_simplex\""));
+        assert_round_trip(&Terrace::new());
+    }
+
+    #[test]
+    fn height_brush_round_trip() {
+        let mut brush = TerrainHeightBrush::new(HeightBrushType::Smooth{strength: 0.5, iterations: 2});
+        brush.falloff = Falloff::Curve(vec![[0.0, 1.0], [1.0, 0.0]]);
+        brush.reselection = false;
+        brush.stroke_height = Some(3.0);
+        let json = assert_round_trip(&brush);
+        let back: TerrainHeightBrush = serde_json::from_str(&json).unwrap();
+        assert!(!back.reselection);
+        assert!(back.stroke_height.is_none());
+
+        // Optional fields fall back to their defaults
+        let brush: TerrainHeightBrush = serde_json::from_str(r#"{"typ": {"value": 1.0}}"#).unwrap();
+        assert!(brush.reselection);
+        assert!(matches!(brush.falloff, Falloff::Constant));
+    }
+
+    #[test]
+    fn color_brush_round_trip() {
+        let types = [
+            ColorBrushType::Value{clr: [0.1, 0.2, 0.3, 1.0]},
+            ColorBrushType::Range{min: 0.0, max: 5.0, min_clr: [0.0, 0.0, 0.0, 1.0], max_clr: [1.0, 1.0, 1.0, 1.0]},
+            ColorBrushType::Noise{data: vec![Noise::new()], value: 1.0, clr: [0.5, 0.5, 0.5, 1.0]},
+            ColorBrushType::Expression{expr: NoiseExpr::Noise(Noise::new()), clr: [1.0, 0.0, 0.0, 1.0]}
+        ];
+        for typ in types.into_iter(){
+            assert_round_trip(&typ);
+            let mut brush = TerrainColorBrush::new(typ);
+            brush.falloff = Falloff::Gaussian;
+            assert_round_trip(&brush);
+        }
+    }
+
+    #[test]
+    fn smooth_edits_chunks_as_one_surface() {
+        use bevy::ecs::system::RunSystemOnce;
+        use crate::chunks::{ChunkLayout, spawn_chunked_terrain};
+        use crate::heightfield::init_heightfields;
+
+        let mut app = App::new();
+        app
+        .add_plugins(bevy::transform::TransformPlugin)
+        .init_resource::<Assets<Mesh>>()
+        .add_systems(PreUpdate, init_heightfields);
+        let layout = ChunkLayout::new(2, 1, 4.0, 4.0, 3);
+        let terrain_entity = app.world_mut().run_system_once(move |mut commands: Commands, mut meshes: ResMut<Assets<Mesh>>| {
+            return spawn_chunked_terrain(&mut commands, &mut meshes, layout, MeshMaterial3d::default());
+        }).unwrap();
+        app.update();
+
+        // Bumps on both sides of the seam and on the seam itself
+        let terrain = app.world().get::<ChunkedTerrain>(terrain_entity).unwrap().clone();
+        let (cols, rows) = layout.grid_size();
+        let initial: Vec<f32> = (0..cols*rows).map(|i| ((i % cols) as f32*1.3).sin()*2.0 + (i / cols) as f32*0.5).collect();
+        for (global_index, height) in initial.iter().enumerate(){
+            for (chunk_entity, index) in terrain.copies(global_index % cols, global_index / cols){
+                app.world_mut().get_mut::<TerrainHeightfield>(chunk_entity).unwrap().heights[index] = *height;
+            }
+        }
+
+        let mut brush = TerrainHeightBrush::new(HeightBrushType::Smooth{strength: 1.0, iterations: 2});
+        brush.started(app.world_mut());
+        brush.apply(app.world_mut(), Vec3::ZERO, 20.0);
+        brush.done(app.world_mut());
+
+        let mut expected = initial.clone();
+        let targets: Vec<(usize, f32)> = (0..cols*rows).map(|index| (index, 1.0)).collect();
+        smooth_heights(&mut expected, cols, rows, &targets, 2, false);
+        for (global_index, height) in expected.iter().enumerate(){
+            for (chunk_entity, index) in terrain.copies(global_index % cols, global_index / cols){
+                assert_eq!(app.world().get::<TerrainHeightfield>(chunk_entity).unwrap().heights[index], *height, "vertex {global_index}");
+            }
+        }
     }
-    fn started(&mut self, world: &mut World) {}
 }
@@ -6,8 +6,16 @@ use bevy::light::{NotShadowCaster, NotShadowReceiver};
 use bevy::color::palettes::css::ORANGE_RED;
 use bevy_enhanced_input::prelude::*;
 use bevy_enhanced_input::prelude::Press;
+use std::ops::Range;
 
-use crate::planes::PlaneToEdit;
+use crate::planes::{PlaneToEdit, update_plane_bounds};
+use crate::history::{BrushHistory, UndoStroke, RedoStroke, undo_stroke, redo_stroke, undo_brush_stroke, redo_brush_stroke};
+use crate::save::{SaveTerrain, SaveChunkedTerrain, TerrainSaveSettings, save_terrain, load_terrain, save_chunked_terrain, load_chunked_terrain};
+use crate::erosion::erode_plane;
+use crate::heightmap::load_heightmap;
+use crate::normals::{TerrainShading, update_normals, shading_changed};
+use crate::heightfield::{TerrainHeightfield, init_heightfields};
+use crate::chunks::{sync_chunk_seams, chunk_seam_normals};
 
 pub struct TerrainEditorVertexPlugin {
     pub vertex_radius: f32
@@ -33,7 +41,21 @@ impl Plugin for TerrainEditorVertexPlugin {
         .add_observer(select_vertex)
         .add_observer(deselect_vertex)
         .add_observer(deselect_all_vertices)
-        .add_systems(Update, vertex_changed)
+        .init_resource::<BrushHistory>()
+        .add_observer(undo_stroke)
+        .add_observer(redo_stroke)
+        .add_observer(undo_brush_stroke)
+        .add_observer(redo_brush_stroke)
+        .add_systems(PreUpdate, init_heightfields)
+        .add_systems(Update, (sync_chunk_seams, vertex_changed, shading_changed, chunk_seam_normals).chain())
+        .add_systems(Update, update_plane_bounds)
+        .init_resource::<TerrainSaveSettings>()
+        .add_observer(save_terrain)
+        .add_observer(load_terrain)
+        .add_observer(save_chunked_terrain)
+        .add_observer(load_chunked_terrain)
+        .add_observer(load_heightmap)
+        .add_observer(erode_plane)
         .add_observer(serialize_planes)
         ;
     }
@@ -44,16 +66,11 @@ impl Plugin for TerrainEditorVertexPlugin {
 struct SerializePlanes;
 
 fn serialize_planes(
-    _trigger: On<Fire<SerializePlanes>>,
-    meshes:   Res<Assets<Mesh>>,
-    query:   Query<&Mesh3d, With<PlaneToEdit>>
+    _trigger:     On<Fire<SerializePlanes>>,
+    mut commands: Commands
 ){
-    for mesh3d in query.iter(){
-        let Some(mesh) = meshes.get(&mesh3d.0) else {continue;};
-        let serialized_mesh = SerializedMesh::from_mesh(mesh.clone());
-        let json = serde_json::to_string_pretty(&serialized_mesh).unwrap();
-        let _a = std::fs::write("assets/meshes/mesh_serialized.json", json);
-    }
+    commands.trigger(SaveTerrain::all());
+    commands.trigger(SaveChunkedTerrain::all());
 }
 
 pub fn load_mesh_from_file(path: &str) -> std::io::Result<Mesh> {
@@ -76,6 +93,16 @@ pub fn terrain_vertex_controller() -> impl Bundle {
                     Press::default(),
                     bindings![MouseButton::Right]
                 ),
+                (
+                    Action::<UndoStroke>::new(),
+                    Press::default(),
+                    bindings![KeyCode::KeyZ]
+                ),
+                (
+                    Action::<RedoStroke>::new(),
+                    Press::default(),
+                    bindings![KeyCode::KeyY]
+                ),
                 (
                     Action::<SerializePlanes>::new(),
                     Press::default(),
@@ -149,17 +176,51 @@ impl PlaneVertex {
 #[derive(Component)]
 pub struct SelectedVertex;
 
+// Vertex marker entities of a plane, indexed like its heightfield
+#[derive(Component)]
+pub struct VertexMarkers(pub Vec<Entity>);
+
 pub fn extract_mesh_data(mesh: &Mesh) -> (Vec<[f32; 3]>, Vec<[f32; 4]>){
-    let v_pos: Vec<[f32; 3]> = mesh.attribute(Mesh::ATTRIBUTE_POSITION).unwrap().as_float3().unwrap().to_vec();
-    let mut v_clr: Vec<[f32; 4]> = Vec::new();
-    if let Some(attr_vcolor) = mesh.attribute(Mesh::ATTRIBUTE_COLOR) {
-        if let VertexAttributeValues::Float32x4(vcolors) = attr_vcolor {
-            v_clr = vcolors.to_vec();
+    let Some(positions) = mesh.attribute(Mesh::ATTRIBUTE_POSITION).and_then(|attr| attr.as_float3()) else {
+        return (Vec::new(), Vec::new());
+    };
+    let v_pos: Vec<[f32; 3]> = positions.to_vec();
+    let v_clr: Vec<[f32; 4]> = match mesh.attribute(Mesh::ATTRIBUTE_COLOR) {
+        Some(VertexAttributeValues::Float32x4(vcolors)) if vcolors.len() == v_pos.len() => {vcolors.to_vec()}
+        _ => {vec![[1.0, 1.0, 1.0, 1.0]; v_pos.len()]}
+    };
+    return (v_pos, v_clr);
+}
+
+// Writes the dirty ranges of a heightfield into the plane mesh buffers
+pub fn write_heightfield(mesh: &mut Mesh, heightfield: &TerrainHeightfield, ranges: &[Range<usize>]){
+    let Some(VertexAttributeValues::Float32x3(positions)) = mesh.attribute_mut(Mesh::ATTRIBUTE_POSITION) else {return;};
+    // Flat shaded planes keep their grid first, update_normals copies it to the face corners
+    if positions.len() < heightfield.len() {
+        return;
+    }
+    for range in ranges.iter(){
+        for index in range.clone(){
+            positions[index] = heightfield.local_position(index).into();
         }
-    } else {
-        v_clr = vec![[1.0, 1.0, 1.0, 1.0]; v_pos.len()];
     }
-    return (v_pos, v_clr);
+    let count = positions.len();
+    let Some(colors) = vertex_colors_mut(mesh, count) else {return;};
+    for range in ranges.iter(){
+        colors[range.clone()].copy_from_slice(&heightfield.colors[range.clone()]);
+    }
+}
+
+// Float32x4 color buffer of the mesh, a missing or differently shaped one is replaced by white once
+fn vertex_colors_mut(mesh: &mut Mesh, count: usize) -> Option<&mut Vec<[f32;4]>> {
+    let has_colors = matches!(mesh.attribute(Mesh::ATTRIBUTE_COLOR), Some(VertexAttributeValues::Float32x4(colors)) if colors.len() == count);
+    if !has_colors {
+        mesh.insert_attribute(Mesh::ATTRIBUTE_COLOR, vec![[1.0_f32, 1.0, 1.0, 1.0]; count]);
+    }
+    match mesh.attribute_mut(Mesh::ATTRIBUTE_COLOR) {
+        Some(VertexAttributeValues::Float32x4(colors)) => {return Some(colors);}
+        _ => {return None;}
+    }
 }
 
 #[derive(Event)]
@@ -170,13 +231,19 @@ pub struct SpawnVertices{
 fn init_plane_to_edit(
     trigger:      On<SpawnVertices>,
     mut commands: Commands,
-    query:        Query<&Mesh3d, With<PlaneToEdit>>,
+    query:        Query<(&Mesh3d, &Transform, &PlaneToEdit)>,
     meshes:       Res<Assets<Mesh>>,
     vertex_refs:  Res<VertexRefs>
 ){
-    let Ok(mesh3d) = query.get(trigger.plane_entity) else {return;};
+    let Ok((mesh3d, plane_transform, plane)) = query.get(trigger.plane_entity) else {return;};
     let Some(mesh) = meshes.get(&mesh3d.0) else {return;};
-    let (v_pos, v_clr) = extract_mesh_data(mesh);
+    let (mut v_pos, v_clr) = extract_mesh_data(mesh);
+    v_pos.truncate(plane.vertex_count());
+    // Markers are children of the plane, undo its scale so they stay round
+    let mut vertex_scale: Vec3 = plane_transform.scale.recip();
+    if !vertex_scale.is_finite() {
+        vertex_scale = Vec3::ONE;
+    }
     let mut vertices: Vec<Entity> = Vec::new();
     for (index, pos) in v_pos.iter().enumerate(){
         let entity = commands.spawn((
@@ -184,12 +251,13 @@ fn init_plane_to_edit(
             vertex_refs.mesh_handle.clone(),
             NotShadowCaster,
             NotShadowReceiver,
-            Transform::from_translation(pos.clone().into()).with_scale(Vec3::splat(1.0)),
+            Transform::from_translation(pos.clone().into()).with_scale(vertex_scale),
             PlaneVertex::new(index, pos, &v_clr[index], vertex_refs.radius, trigger.plane_entity),
         )).id();
         vertices.push(entity);
     }
     commands.entity(trigger.plane_entity).add_children(&vertices);
+    commands.entity(trigger.plane_entity).insert(VertexMarkers(vertices));
 }
 
 fn on_remove_plane(
@@ -222,12 +290,11 @@ fn deselect_vertex(
 }
 
 fn deselect_all_vertices(
-    _trigger: On<Fire<DeselectAllVertices>>,
-    mut commands: Commands,
-    query:  Query<Entity, With<SelectedVertex>>
+    _trigger:   On<Fire<DeselectAllVertices>>,
+    mut planes: Query<&mut TerrainHeightfield>
 ){
-    for entity in query.iter(){
-        commands.entity(entity).remove::<SelectedVertex>();
+    for mut heightfield in planes.iter_mut(){
+        heightfield.clear_selection();
     }
 }
 
@@ -235,17 +302,139 @@ fn deselect_all_vertices(
 #[action_output(bool)]
 struct DeselectAllVertices;
 
-fn vertex_changed(
-    mut vertices:   Query<&PlaneVertex, (With<SelectedVertex>, Changed<PlaneVertex>)>,
-    plane_mesh3d:   Single<&Mesh3d, With<PlaneToEdit>>,
+type ChangedPlanes<'w, 's> = Query<'w, 's, (
+    Entity,
+    &'static mut TerrainHeightfield,
+    &'static Mesh3d,
+    &'static mut PlaneToEdit,
+    Option<&'static TerrainShading>,
+    Option<&'static VertexMarkers>
+), Changed<TerrainHeightfield>>;
+
+// Pushes heightfield edits to the plane mesh and to the vertex markers when they were spawned.
+// Only the dirty ranges are written, so the work follows the size of the edit and not of the plane.
+pub(crate) fn vertex_changed(
+    mut commands:   Commands,
+    mut planes:     ChangedPlanes,
+    mut markers:    Query<(&mut PlaneVertex, &mut Transform)>,
     mut meshes:     ResMut<Assets<Mesh>>
 ){
-    let Some(plane_mesh) = meshes.get_mut(&plane_mesh3d.0) else {return;};
-    let (mut v_pos, mut v_clr) = extract_mesh_data(plane_mesh);
-    for plane_vertex in vertices.iter_mut(){
-        v_pos[plane_vertex.index] = plane_vertex.loc;
-        v_clr[plane_vertex.index] = plane_vertex.clr;
-    }
-    plane_mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, v_pos);
-    plane_mesh.insert_attribute(Mesh::ATTRIBUTE_COLOR, v_clr);
+    for (plane_entity, mut heightfield, plane_mesh3d, mut plane, shading, vertex_markers) in planes.iter_mut(){
+        if !heightfield.is_dirty() {
+            continue;
+        }
+        let heightfield = heightfield.bypass_change_detection();
+        let dirty_ranges = heightfield.take_dirty_ranges();
+        let selection_changes = heightfield.take_selection_changes();
+
+        if !dirty_ranges.is_empty() && let Some(plane_mesh) = meshes.get_mut(&plane_mesh3d.0) {
+            write_heightfield(plane_mesh, heightfield, &dirty_ranges);
+            let dirty: Vec<usize> = dirty_ranges.iter().cloned().flatten().collect();
+            update_normals(plane_mesh, &plane, shading.copied().unwrap_or_default(), Some(&dirty));
+            if plane.grow_height_range(dirty.iter().map(|index| heightfield.heights[*index])) {
+                commands.entity(plane_entity).insert(plane.aabb());
+            }
+        }
+
+        let Some(vertex_markers) = vertex_markers else {continue;};
+        for index in dirty_ranges.iter().cloned().flatten(){
+            let Some(marker_entity) = vertex_markers.0.get(index) else {continue;};
+            let Ok((mut plane_vertex, mut vertex_transform)) = markers.get_mut(*marker_entity) else {continue;};
+            plane_vertex.loc = heightfield.local_position(index).into();
+            plane_vertex.clr = heightfield.colors[index];
+            vertex_transform.translation = Vec3::from(plane_vertex.loc);
+        }
+        for index in selection_changes.iter(){
+            let Some(marker_entity) = vertex_markers.0.get(*index) else {continue;};
+            if markers.get(*marker_entity).is_err() {
+                continue;
+            }
+            if heightfield.is_selected(*index) {
+                commands.entity(*marker_entity).insert(SelectedVertex);
+            } else {
+                commands.entity(*marker_entity).remove::<SelectedVertex>();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy::ecs::system::RunSystemOnce;
+    use bevy_pg_editor_tools::prelude::BrushType;
+    use crate::planes::plane_mesh;
+    use bevy::camera::primitives::Aabb;
+    use crate::terrain_brushes::{TerrainHeightBrush, HeightBrushType, FlattenTarget};
+
+    fn mesh_positions(app: &App, plane_entity: Entity) -> Vec<[f32;3]> {
+        let mesh3d = app.world().get::<Mesh3d>(plane_entity).unwrap();
+        let mesh = app.world().resource::<Assets<Mesh>>().get(&mesh3d.0).unwrap();
+        return mesh.attribute(Mesh::ATTRIBUTE_POSITION).unwrap().as_float3().unwrap().to_vec();
+    }
+
+    #[test]
+    fn brush_edits_every_plane() {
+        let mut app = App::new();
+        app
+        .init_resource::<Assets<Mesh>>()
+        .add_systems(PreUpdate, init_heightfields)
+        .add_systems(Update, vertex_changed);
+
+        // Two planes side by side, the brush covers both of them
+        let plane_entities = app.world_mut().run_system_once(|mut commands: Commands, mut meshes: ResMut<Assets<Mesh>>| {
+            return [-2.0, 2.0].map(|x| commands.spawn((
+                plane_mesh(4.0, 4.0, 3, &mut meshes),
+                Transform::from_xyz(x, 0.0, 0.0),
+                GlobalTransform::from_xyz(x, 0.0, 0.0)
+            )).id());
+        }).unwrap();
+        app.update();
+        let before: Vec<Vec<[f32;3]>> = plane_entities.iter().map(|plane_entity| mesh_positions(&app, *plane_entity)).collect();
+
+        let mut brush = TerrainHeightBrush::new(HeightBrushType::Value(1.5));
+        brush.started(app.world_mut());
+        brush.apply(app.world_mut(), Vec3::ZERO, 10.0);
+        brush.done(app.world_mut());
+        app.update();
+
+        for (plane_entity, before) in plane_entities.iter().zip(before){
+            let after = mesh_positions(&app, *plane_entity);
+            assert_eq!(after.len(), 25);
+            for (old, new) in before.iter().zip(after.iter()){
+                assert_eq!([old[0], old[1] + 1.5, old[2]], *new);
+            }
+        }
+    }
+
+    #[test]
+    fn bounds_grow_with_edits_and_fit_at_stroke_end() {
+        let mut app = App::new();
+        app
+        .init_resource::<Assets<Mesh>>()
+        .add_systems(PreUpdate, init_heightfields)
+        .add_systems(Update, vertex_changed);
+        let plane_entity = app.world_mut().run_system_once(|mut commands: Commands, mut meshes: ResMut<Assets<Mesh>>| {
+            return commands.spawn(plane_mesh(4.0, 4.0, 3, &mut meshes)).id();
+        }).unwrap();
+        app.update();
+
+        let mut raise = TerrainHeightBrush::new(HeightBrushType::Value(2.0));
+        raise.started(app.world_mut());
+        raise.apply(app.world_mut(), Vec3::ZERO, 0.5);
+        app.update();
+        assert_eq!(app.world().get::<PlaneToEdit>(plane_entity).unwrap().height_range, [0.0, 2.0]);
+        assert_eq!(app.world().get::<Aabb>(plane_entity).unwrap().max().y, 2.0);
+        raise.done(app.world_mut());
+
+        // Flattening everything back only shrinks the bounds once the stroke ends
+        let mut flatten = TerrainHeightBrush::new(HeightBrushType::Flatten{target: FlattenTarget::Value(0.5), strength: 1.0});
+        flatten.started(app.world_mut());
+        flatten.apply(app.world_mut(), Vec3::ZERO, 10.0);
+        app.update();
+        assert_eq!(app.world().get::<PlaneToEdit>(plane_entity).unwrap().height_range, [0.0, 2.0]);
+        flatten.done(app.world_mut());
+        assert_eq!(app.world().get::<PlaneToEdit>(plane_entity).unwrap().height_range, [0.5, 0.5]);
+        assert_eq!(app.world().get::<Aabb>(plane_entity).unwrap().min().y, 0.5);
+    }
 }